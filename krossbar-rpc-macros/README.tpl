@@ -0,0 +1,14 @@
+[![Crates.io][crates-badge]][crates-url]
+[![MIT licensed][mit-badge]][mit-url]
+[![Build Status][actions-badge]][actions-url]
+
+[crates-badge]: https://img.shields.io/crates/v/krossbar-rpc-macros.svg
+[crates-url]: https://crates.io/crates/krossbar-rpc-macros
+[mit-badge]: https://img.shields.io/badge/license-MIT-blue.svg
+[mit-url]: https://github.com/krossbar-platform/krossbar-common/blob/main/LICENSE
+[actions-badge]: https://github.com/krossbar-platform/krossbar-common/actions/workflows/ci.yml/badge.svg
+[actions-url]: https://github.com/krossbar-platform/krossbar-common/actions/workflows/ci.yml
+
+# {{crate}}
+
+{{readme}}
@@ -0,0 +1,468 @@
+//! # Krossbar RPC service macros
+//!
+//! Typed service definitions for [krossbar-rpc](https://crates.io/crates/krossbar-rpc).
+//!
+//! [macro@service] attribute takes a trait, which describes service endpoints, and generates:
+//! - `<Trait>Client` - a typed client over `RpcWriter`;
+//! - `<Trait>Dispatcher` - a dispatcher, which routes incoming `RpcRequest`s to the trait methods.
+//!
+//! Each trait method must be `async`, take `&self`, and be marked with one of the endpoint kinds:
+//! - `#[call]` - a call with zero or one parameter, which returns `krossbar_rpc::Result<T>`;
+//! - `#[message]` - a one-way message with a single parameter;
+//! - `#[subscription]` - a subscription, which takes `SubscriptionSink<T>` to emit items.
+//!
+//! Endpoint name is the method name. Dispatcher responds with `Error::ParamsTypeError` if
+//! incoming params can't be deserialized, and with `Error::NoEndpoint` if there's no such endpoint.
+//! Subscription handlers are spawned, so the service must be `Send + Sync + 'static`.
+//!
+//! # Examples
+//! ```rust
+//! use krossbar_rpc::{rpc::Rpc, subscription::SubscriptionSink};
+//! use krossbar_rpc_macros::service;
+//!
+//! #[service]
+//! pub trait Calculator {
+//!     #[call]
+//!     async fn add(&self, params: (i32, i32)) -> krossbar_rpc::Result<i32>;
+//!
+//!     #[message]
+//!     async fn log(&self, message: String);
+//!
+//!     #[subscription]
+//!     async fn results(&self, sink: SubscriptionSink<i32>);
+//! }
+//!
+//! struct Service;
+//!
+//! impl Calculator for Service {
+//!     async fn add(&self, (a, b): (i32, i32)) -> krossbar_rpc::Result<i32> {
+//!         Ok(a + b)
+//!     }
+//!
+//!     async fn log(&self, message: String) {
+//!         println!("{message}")
+//!     }
+//!
+//!     async fn results(&self, sink: SubscriptionSink<i32>) {
+//!         sink.send(42).await;
+//!         sink.close().await;
+//!     }
+//! }
+//!
+//! async fn serve(mut rpc: Rpc) {
+//!     let dispatcher = CalculatorDispatcher::new(Service);
+//!
+//!     while let Ok(request) = rpc.poll().await {
+//!         dispatcher.dispatch(request).await
+//!     }
+//! }
+//!
+//! async fn call(mut rpc: Rpc) {
+//!     let client = CalculatorClient::new(rpc.writer().clone());
+//!
+//!     futures::select! {
+//!         result = futures::FutureExt::fuse(client.add(&(40, 2))) => println!("Result: {result:?}"),
+//!         _ = futures::FutureExt::fuse(rpc.poll()) => {}
+//!     }
+//! }
+//! ```
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, FnArg, GenericArgument, Ident, ItemTrait,
+    PathArguments, ReturnType, TraitItem, TraitItemFn, Type,
+};
+
+/// Endpoint kind
+enum Kind {
+    Call,
+    Message,
+    Subscription,
+}
+
+/// Parsed service method
+struct Endpoint {
+    kind: Kind,
+    /// Method name, which is also an endpoint name
+    name: Ident,
+    /// Call or message parameter type
+    param: Option<Type>,
+    /// Call result, or subscription item type
+    result: Option<Type>,
+}
+
+/// Generate typed client and dispatcher for a service trait. See crate docs for the details
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(
+            Span::call_site(),
+            "`service` attribute doesn't take arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let mut item_trait = parse_macro_input!(item as ItemTrait);
+
+    match expand(&mut item_trait) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(item_trait: &mut ItemTrait) -> syn::Result<TokenStream2> {
+    if !item_trait.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            item_trait.generics.span(),
+            "Generic service traits are not supported",
+        ));
+    }
+
+    let mut endpoints = Vec::new();
+
+    for item in item_trait.items.iter_mut() {
+        if let TraitItem::Fn(method) = item {
+            endpoints.push(parse_endpoint(method)?);
+            desugar_async(method);
+        }
+    }
+
+    let client = client(item_trait, &endpoints);
+    let dispatcher = dispatcher(item_trait, &endpoints);
+
+    Ok(quote! {
+        #item_trait
+
+        #client
+
+        #dispatcher
+    })
+}
+
+/// Parse endpoint description from a trait method, removing endpoint kind attribute
+fn parse_endpoint(method: &mut TraitItemFn) -> syn::Result<Endpoint> {
+    let mut kind = None;
+
+    method.attrs.retain(|attr| {
+        let attr_kind = if attr.path().is_ident("call") {
+            Kind::Call
+        } else if attr.path().is_ident("message") {
+            Kind::Message
+        } else if attr.path().is_ident("subscription") {
+            Kind::Subscription
+        } else {
+            return true;
+        };
+
+        kind = Some(attr_kind);
+        false
+    });
+
+    let sig = &method.sig;
+
+    let kind = kind.ok_or_else(|| {
+        syn::Error::new(
+            sig.ident.span(),
+            "Service methods must be marked with #[call], #[message], or #[subscription]",
+        )
+    })?;
+
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new(
+            sig.span(),
+            "Service methods must be `async`",
+        ));
+    }
+
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver))
+            if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+        _ => {
+            return Err(syn::Error::new(
+                sig.span(),
+                "Service methods must take `&self`",
+            ))
+        }
+    }
+
+    let mut params = Vec::new();
+    for input in inputs {
+        if let FnArg::Typed(arg) = input {
+            params.push((*arg.ty).clone())
+        }
+    }
+
+    let returns_unit = match &sig.output {
+        ReturnType::Default => true,
+        ReturnType::Type(_, ty) => {
+            matches!(ty.as_ref(), Type::Tuple(tuple) if tuple.elems.is_empty())
+        }
+    };
+
+    match kind {
+        Kind::Call => {
+            if params.len() > 1 {
+                return Err(syn::Error::new(
+                    sig.inputs.span(),
+                    "Calls take at most one parameter. Use a tuple or a struct for multiple parameters",
+                ));
+            }
+
+            let result = match &sig.output {
+                ReturnType::Type(_, ty) => first_generic_arg(ty, "Result"),
+                ReturnType::Default => None,
+            }
+            .ok_or_else(|| {
+                syn::Error::new(
+                    sig.output.span(),
+                    "Calls must return `krossbar_rpc::Result<T>`",
+                )
+            })?;
+
+            Ok(Endpoint {
+                kind,
+                name: sig.ident.clone(),
+                param: params.pop(),
+                result: Some(result),
+            })
+        }
+        Kind::Message => {
+            if params.len() != 1 || !returns_unit {
+                return Err(syn::Error::new(
+                    sig.span(),
+                    "Messages take exactly one parameter and return nothing",
+                ));
+            }
+
+            Ok(Endpoint {
+                kind,
+                name: sig.ident.clone(),
+                param: params.pop(),
+                result: None,
+            })
+        }
+        Kind::Subscription => {
+            let item = match params.as_slice() {
+                [ty] if returns_unit => first_generic_arg(ty, "SubscriptionSink"),
+                _ => None,
+            }
+            .ok_or_else(|| {
+                syn::Error::new(
+                    sig.span(),
+                    "Subscriptions take exactly one `SubscriptionSink<T>` parameter and return nothing",
+                )
+            })?;
+
+            Ok(Endpoint {
+                kind,
+                name: sig.ident.clone(),
+                param: None,
+                result: Some(item),
+            })
+        }
+    }
+}
+
+/// Get first generic argument of a type, which last path segment is `ident`, e.g. `T` for `Result<T>`
+fn first_generic_arg(ty: &Type, ident: &str) -> Option<Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+
+    let segment = path.path.segments.last()?;
+    if segment.ident != ident {
+        return None;
+    }
+
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+
+    args.args.iter().find_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    })
+}
+
+/// Replace `async fn` with a function returning `Send` future, so service futures can be spawned
+fn desugar_async(method: &mut TraitItemFn) {
+    let sig = &mut method.sig;
+    sig.asyncness = None;
+
+    let output = match &sig.output {
+        ReturnType::Default => quote! { () },
+        ReturnType::Type(_, ty) => quote! { #ty },
+    };
+
+    sig.output = parse_quote! {
+        -> impl ::std::future::Future<Output = #output> + Send
+    };
+
+    if let Some(block) = method.default.take() {
+        method.default = Some(parse_quote! {
+            { async move #block }
+        });
+    }
+}
+
+fn client(item_trait: &ItemTrait, endpoints: &[Endpoint]) -> TokenStream2 {
+    let vis = &item_trait.vis;
+    let trait_name = &item_trait.ident;
+    let client_name = format_ident!("{}Client", trait_name);
+    let doc = format!("Typed [{trait_name}] client");
+
+    let methods = endpoints.iter().map(|endpoint| {
+        let name = &endpoint.name;
+        let endpoint_name = name.to_string();
+        let result = &endpoint.result;
+
+        match (&endpoint.kind, &endpoint.param) {
+            (Kind::Call, Some(param)) => quote! {
+                pub async fn #name(&self, params: &#param) -> ::krossbar_rpc::Result<#result> {
+                    self.writer.call::<#param, #result>(#endpoint_name, params).await?.await
+                }
+            },
+            (Kind::Call, None) => quote! {
+                pub async fn #name(&self) -> ::krossbar_rpc::Result<#result> {
+                    self.writer.call::<(), #result>(#endpoint_name, &()).await?.await
+                }
+            },
+            (Kind::Message, param) => quote! {
+                pub async fn #name(&self, body: &#param) -> ::krossbar_rpc::Result<()> {
+                    self.writer.send_message(#endpoint_name, body).await
+                }
+            },
+            (Kind::Subscription, _) => quote! {
+                pub async fn #name(
+                    &self,
+                ) -> ::krossbar_rpc::Result<::krossbar_rpc::subscription::Subscription<#result>> {
+                    self.writer.subscribe::<#result>(#endpoint_name).await
+                }
+            },
+        }
+    });
+
+    quote! {
+        #[doc = #doc]
+        #[derive(Clone)]
+        #vis struct #client_name {
+            writer: ::krossbar_rpc::writer::RpcWriter,
+        }
+
+        impl #client_name {
+            pub fn new(writer: ::krossbar_rpc::writer::RpcWriter) -> Self {
+                Self { writer }
+            }
+
+            /// Underlying RPC writer
+            pub fn writer(&self) -> &::krossbar_rpc::writer::RpcWriter {
+                &self.writer
+            }
+
+            #(#methods)*
+        }
+    }
+}
+
+fn dispatcher(item_trait: &ItemTrait, endpoints: &[Endpoint]) -> TokenStream2 {
+    let vis = &item_trait.vis;
+    let trait_name = &item_trait.ident;
+    let dispatcher_name = format_ident!("{}Dispatcher", trait_name);
+    let doc = format!("Dispatcher, which routes incoming requests to [{trait_name}] methods");
+
+    let arms = endpoints.iter().map(|endpoint| {
+        let name = &endpoint.name;
+        let endpoint_name = name.to_string();
+
+        match (&endpoint.kind, &endpoint.param) {
+            (Kind::Call, Some(param)) => quote! {
+                (#endpoint_name, Some(::krossbar_rpc::request::Body::Call(params))) => {
+                    match ::krossbar_rpc::bson::from_bson::<#param>(params) {
+                        Ok(params) => {
+                            let result = self.service.#name(params).await;
+                            request.respond(result).await;
+                        }
+                        Err(e) => {
+                            request
+                                .respond::<()>(Err(::krossbar_rpc::Error::ParamsTypeError(e.to_string())))
+                                .await;
+                        }
+                    }
+                }
+            },
+            (Kind::Call, None) => quote! {
+                (#endpoint_name, Some(::krossbar_rpc::request::Body::Call(_))) => {
+                    let result = self.service.#name().await;
+                    request.respond(result).await;
+                }
+            },
+            (Kind::Message, param) => quote! {
+                (#endpoint_name, Some(::krossbar_rpc::request::Body::Message(body))) => {
+                    // Nothing to respond to if body has invalid type
+                    if let Ok(body) = ::krossbar_rpc::bson::from_bson::<#param>(body) {
+                        self.service.#name(body).await
+                    }
+                }
+            },
+            (Kind::Subscription, _) => quote! {
+                (#endpoint_name, Some(::krossbar_rpc::request::Body::Subscription)) => {
+                    // Subscriptions may live as long as the connection, so the handler is
+                    // spawned to not block the caller polling the connection
+                    let service = self.service.clone();
+                    let sink = request.into_subscription();
+
+                    ::krossbar_rpc::tokio::spawn(async move { service.#name(sink).await });
+                }
+            },
+        }
+    });
+
+    quote! {
+        #[doc = #doc]
+        #vis struct #dispatcher_name<S> {
+            service: ::std::sync::Arc<S>,
+        }
+
+        impl<S: #trait_name + Send + Sync + 'static> #dispatcher_name<S> {
+            pub fn new(service: S) -> Self {
+                Self {
+                    service: ::std::sync::Arc::new(service),
+                }
+            }
+
+            /// Service implementation
+            pub fn service(&self) -> &S {
+                &self.service
+            }
+
+            /// Dispatch incoming request to the service.
+            /// Responds with `Error::NoEndpoint` if the service doesn't have a requested endpoint.
+            /// Calls and messages are handled before returning. Subscription handlers are spawned,
+            /// so the connection keeps being polled while subscriptions are active.
+            /// Must be called within Tokio runtime
+            pub async fn dispatch(&self, mut request: ::krossbar_rpc::request::RpcRequest) {
+                let endpoint = request.endpoint().clone();
+
+                match (endpoint.as_str(), request.take_body()) {
+                    #(#arms)*
+                    // Can't respond to one-way messages and connection requests
+                    (
+                        _,
+                        Some(
+                            ::krossbar_rpc::request::Body::Message(_)
+                            | ::krossbar_rpc::request::Body::Fd { .. },
+                        ),
+                    ) => {}
+                    _ => {
+                        request
+                            .respond::<()>(Err(::krossbar_rpc::Error::NoEndpoint))
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+}
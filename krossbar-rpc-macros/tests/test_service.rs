@@ -0,0 +1,222 @@
+use std::time::Duration;
+
+use futures::{select, FutureExt, StreamExt};
+use krossbar_rpc::{request::Body, rpc::Rpc, subscription::SubscriptionSink};
+use krossbar_rpc_macros::service;
+use serde::{Deserialize, Serialize};
+use tokio::{net::UnixStream, sync::mpsc};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AddParams {
+    a: i32,
+    b: i32,
+}
+
+#[service]
+pub trait Calculator {
+    /// Add two numbers
+    #[call]
+    async fn add(&self, params: AddParams) -> krossbar_rpc::Result<i32>;
+
+    #[call]
+    async fn version(&self) -> krossbar_rpc::Result<String>;
+
+    #[message]
+    async fn log(&self, message: String);
+
+    #[subscription]
+    async fn counter(&self, sink: SubscriptionSink<u32>);
+
+    /// Emits a single item, and lives until the subscriber unsubscribes
+    #[subscription]
+    async fn events(&self, sink: SubscriptionSink<u32>);
+}
+
+struct Service {
+    messages: mpsc::UnboundedSender<String>,
+}
+
+impl Calculator for Service {
+    async fn add(&self, params: AddParams) -> krossbar_rpc::Result<i32> {
+        Ok(params.a + params.b)
+    }
+
+    async fn version(&self) -> krossbar_rpc::Result<String> {
+        Err(krossbar_rpc::Error::ClientError("No version".to_owned()))
+    }
+
+    async fn log(&self, message: String) {
+        self.messages.send(message).unwrap();
+    }
+
+    async fn counter(&self, sink: SubscriptionSink<u32>) {
+        for i in 0..3 {
+            sink.send(i).await;
+        }
+
+        sink.close().await;
+    }
+
+    async fn events(&self, sink: SubscriptionSink<u32>) {
+        sink.send(42).await;
+        sink.cancelled().await;
+    }
+}
+
+fn make_service() -> (
+    CalculatorDispatcher<Service>,
+    mpsc::UnboundedReceiver<String>,
+) {
+    let (messages, receiver) = mpsc::unbounded_channel();
+
+    (CalculatorDispatcher::new(Service { messages }), receiver)
+}
+
+async fn serve(mut rpc: Rpc, dispatcher: CalculatorDispatcher<Service>) {
+    while let Ok(request) = rpc.poll().await {
+        dispatcher.dispatch(request).await
+    }
+}
+
+#[tokio::test]
+async fn test_service_calls() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let rpc2 = Rpc::new(stream2, "service");
+
+    let (dispatcher, _) = make_service();
+    tokio::spawn(serve(rpc2, dispatcher));
+
+    let client = CalculatorClient::new(rpc1.writer().clone());
+
+    select! {
+        response = client.add(&AddParams { a: 40, b: 2 }).fuse() => {
+            assert_eq!(response.unwrap(), 42);
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    select! {
+        response = client.version().fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::ClientError(_))));
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
+
+#[tokio::test]
+async fn test_service_message() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "client");
+    let rpc2 = Rpc::new(stream2, "service");
+
+    let (dispatcher, mut messages) = make_service();
+    tokio::spawn(serve(rpc2, dispatcher));
+
+    let client = CalculatorClient::new(rpc1.writer().clone());
+    client.log(&"Hello".to_owned()).await.unwrap();
+
+    assert_eq!(messages.recv().await.unwrap(), "Hello");
+}
+
+#[tokio::test]
+async fn test_service_subscription() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let rpc2 = Rpc::new(stream2, "service");
+
+    let (dispatcher, _) = make_service();
+    tokio::spawn(serve(rpc2, dispatcher));
+
+    let client = CalculatorClient::new(rpc1.writer().clone());
+    let subscription = client.counter().await.unwrap();
+
+    select! {
+        response = subscription.collect::<Vec<krossbar_rpc::Result<u32>>>().fuse() => {
+            let values: Vec<u32> = response.into_iter().map(Result::unwrap).collect();
+            assert_eq!(values, vec![0, 1, 2]);
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
+
+#[tokio::test]
+async fn test_service_errors() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let rpc2 = Rpc::new(stream2, "service");
+
+    let (dispatcher, _) = make_service();
+    tokio::spawn(serve(rpc2, dispatcher));
+
+    let call = rpc1.call::<u32, i32>("add", &42).await.unwrap();
+
+    select! {
+        response = call.fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::ParamsTypeError(_))));
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    let call = rpc1.call::<u32, i32>("multiply", &42).await.unwrap();
+
+    select! {
+        response = call.fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::NoEndpoint)));
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
+
+#[tokio::test]
+async fn test_dispatch_body_kind_mismatch() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+
+    let (dispatcher, _) = make_service();
+
+    // Subscribing to a call endpoint
+    let mut subscription = rpc1.subscribe::<u32>("add").await.unwrap();
+
+    let request = rpc2.poll().await.unwrap();
+    assert!(matches!(request.body(), Some(Body::Subscription)));
+    dispatcher.dispatch(request).await;
+
+    select! {
+        response = subscription.next() => {
+            assert!(matches!(response, Some(Err(krossbar_rpc::Error::NoEndpoint))));
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
+
+#[tokio::test]
+async fn test_service_long_subscription() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let rpc2 = Rpc::new(stream2, "service");
+
+    let (dispatcher, _) = make_service();
+    tokio::spawn(serve(rpc2, dispatcher));
+
+    let client = CalculatorClient::new(rpc1.writer().clone());
+    let mut subscription = client.events().await.unwrap();
+
+    // Calls are handled while the subscription is active
+    let requests = async {
+        assert_eq!(subscription.next().await.unwrap().unwrap(), 42);
+        assert_eq!(client.add(&AddParams { a: 40, b: 2 }).await.unwrap(), 42);
+    };
+
+    select! {
+        result = tokio::time::timeout(Duration::from_secs(5), requests).fuse() => result.unwrap(),
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
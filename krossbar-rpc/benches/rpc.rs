@@ -0,0 +1,285 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{lock::Mutex, StreamExt};
+use tokio::{net::UnixStream, runtime::Runtime, task::JoinSet};
+
+use krossbar_rpc::{bson::Bson, request::Body, rpc::Rpc, writer::RpcWriter, CallsRegistry};
+
+const PAYLOAD_SIZES: [usize; 4] = [16, 1024, 16 * 1024, 256 * 1024];
+const SUBSCRIPTION_ITEMS: u32 = 100;
+const FAN_OUT_SIZES: [usize; 3] = [1, 10, 100];
+const REGISTRY_TASKS: usize = 100;
+const REGISTRY_OPS: usize = 100;
+
+/// Registry, which was shared behind a single async mutex before [CallsRegistry] was sharded.
+/// Used as a reference for the sharded registry. FD calls are omitted, because the benchmark
+/// doesn't make them
+mod baseline {
+    use std::collections::HashMap;
+
+    use futures::{
+        channel::{
+            mpsc::Sender,
+            oneshot::{channel as one_channel, Receiver as OneReceiver, Sender as OneSender},
+        },
+        SinkExt,
+    };
+    use krossbar_rpc::bson::Bson;
+    use log::{debug, trace, warn};
+
+    #[derive(Default)]
+    pub struct CallsRegistry {
+        id_counter: i64,
+        /// Active calls
+        calls: HashMap<i64, OneSender<krossbar_rpc::Result<Bson>>>,
+        /// Current subscriptions
+        subscriptions: HashMap<i64, Sender<krossbar_rpc::Result<Bson>>>,
+        /// Persistent calls to send on reconnect
+        active_subscriptions: HashMap<i64, krossbar_rpc::RpcData>,
+    }
+
+    impl CallsRegistry {
+        pub fn add_call(&mut self) -> (i64, OneReceiver<krossbar_rpc::Result<Bson>>) {
+            let (sender, receiver) = one_channel();
+            let id = self.next_id();
+
+            self.calls.insert(id, sender);
+
+            trace!("Add new call");
+
+            (id, receiver)
+        }
+
+        pub async fn resolve(&mut self, message_id: i64, response: krossbar_rpc::Result<Bson>) {
+            // Try to resolve an active call
+            if let Some(channel) = self.calls.remove(&message_id) {
+                if channel.send(response).is_err() {
+                    warn!("User dropped call handle. Failed to send a response")
+                } else {
+                    debug!("Succesfully resolved {message_id} call")
+                }
+            } else if let Some(channel) = self.subscriptions.get_mut(&message_id) {
+                // Subscription is no longer active
+                if !self.active_subscriptions.contains_key(&message_id) {
+                    debug!("Inactive subscription response")
+                // Try to resolve an active subscription
+                } else if channel.send(response).await.is_err() {
+                    warn!("User dropped subscriptions handle. Failed to send a response");
+
+                    // Remove subscription as no one is waiting for it anymore
+                    self.active_subscriptions.remove(&message_id);
+                // No such call
+                } else {
+                    debug!("Succesfully resolved {message_id} subscription")
+                }
+            } else {
+                warn!("Unexpected peer response: {:?}", response)
+            }
+        }
+
+        fn next_id(&mut self) -> i64 {
+            self.id_counter += 1;
+            self.id_counter
+        }
+    }
+}
+
+/// Serve benchmark requests:
+/// - `echo` call responds with the call params;
+/// - `fd` call responds with a new stream;
+/// - `counter` subscription sends [SUBSCRIPTION_ITEMS] items and closes the stream;
+/// - messages are dropped
+async fn serve(mut rpc: Rpc) {
+    while let Ok(mut request) = rpc.poll().await {
+        match request.take_body() {
+            Some(Body::Call(params)) if request.endpoint() == "fd" => {
+                let (stream, _) = UnixStream::pair().unwrap();
+                request
+                    .respond_with_fd(Ok(params), vec![stream.try_into().unwrap()])
+                    .await;
+            }
+            Some(Body::Call(params)) => {
+                request.respond(Ok(params)).await;
+            }
+            Some(Body::Subscription) => {
+                let sink = request.into_subscription();
+                for i in 0..SUBSCRIPTION_ITEMS {
+                    sink.send(i).await;
+                }
+                sink.close().await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Make a connected pair, returning client writer. Client and service are polled in background
+fn connect(runtime: &Runtime) -> RpcWriter {
+    runtime.block_on(async {
+        let (stream1, stream2) = UnixStream::pair().unwrap();
+
+        let mut client = Rpc::new(stream1, "service");
+        let writer = client.writer().clone();
+
+        tokio::spawn(async move { while client.poll().await.is_ok() {} });
+        tokio::spawn(serve(Rpc::new(stream2, "client")));
+
+        writer
+    })
+}
+
+fn bench_call(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let writer = connect(&runtime);
+
+    let mut group = c.benchmark_group("call");
+    for size in PAYLOAD_SIZES {
+        let payload = "x".repeat(size);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &payload, |b, payload| {
+            b.to_async(&runtime).iter(|| async {
+                writer
+                    .call::<String, String>("echo", payload)
+                    .await
+                    .unwrap()
+                    .await
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_send_message(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let writer = connect(&runtime);
+
+    let mut group = c.benchmark_group("send_message");
+    for size in PAYLOAD_SIZES {
+        let payload = "x".repeat(size);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &payload, |b, payload| {
+            b.to_async(&runtime)
+                .iter(|| async { writer.send_message("message", payload).await.unwrap() })
+        });
+    }
+    group.finish();
+}
+
+fn bench_subscribe(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let writer = connect(&runtime);
+
+    let mut group = c.benchmark_group("subscribe");
+    for fan_out in FAN_OUT_SIZES {
+        group.throughput(Throughput::Elements(
+            fan_out as u64 * SUBSCRIPTION_ITEMS as u64,
+        ));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(fan_out),
+            &fan_out,
+            |b, &fan_out| {
+                b.to_async(&runtime).iter(|| async {
+                    let mut subscriptions = Vec::with_capacity(fan_out);
+                    for _ in 0..fan_out {
+                        subscriptions.push(writer.subscribe::<u32>("counter").await.unwrap());
+                    }
+
+                    futures::future::join_all(
+                        subscriptions
+                            .into_iter()
+                            .map(|subscription| subscription.count()),
+                    )
+                    .await
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_call_fd(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let writer = connect(&runtime);
+
+    let mut group = c.benchmark_group("call_fd");
+    for size in PAYLOAD_SIZES {
+        let payload = "x".repeat(size);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &payload, |b, payload| {
+            b.to_async(&runtime).iter(|| async {
+                writer
+                    .call_fd::<String, String>("fd", payload)
+                    .await
+                    .unwrap()
+                    .await
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Add and resolve calls from [REGISTRY_TASKS] concurrent tasks
+fn bench_registry(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+
+    let mut group = c.benchmark_group("registry");
+    group.throughput(Throughput::Elements((REGISTRY_TASKS * REGISTRY_OPS) as u64));
+
+    group.bench_function("sharded", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let registry = Arc::new(CallsRegistry::new());
+            let mut set = JoinSet::new();
+
+            for _ in 0..REGISTRY_TASKS {
+                let registry = registry.clone();
+                set.spawn(async move {
+                    for _ in 0..REGISTRY_OPS {
+                        let (id, receiver) = registry.add_call(None);
+                        registry.resolve(id, Ok(Bson::default()));
+                        receiver.await.unwrap().unwrap();
+                    }
+                });
+            }
+
+            set.join_all().await
+        })
+    });
+
+    group.bench_function("single_mutex", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let registry = Arc::new(Mutex::new(baseline::CallsRegistry::default()));
+            let mut set = JoinSet::new();
+
+            for _ in 0..REGISTRY_TASKS {
+                let registry = registry.clone();
+                set.spawn(async move {
+                    for _ in 0..REGISTRY_OPS {
+                        let (id, receiver) = registry.lock().await.add_call();
+                        registry.lock().await.resolve(id, Ok(Bson::default())).await;
+                        receiver.await.unwrap().unwrap();
+                    }
+                });
+            }
+
+            set.join_all().await
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_call,
+    bench_send_message,
+    bench_subscribe,
+    bench_call_fd,
+    bench_registry
+);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+//! Incoming requests authorization.
+//!
+//! Set an [AccessPolicy] using [crate::rpc::Rpc::with_access_policy] to check every incoming
+//! request before it's returned from [crate::rpc::Rpc::poll]. Disallowed calls and subscriptions
+//! are automatically responded with [crate::Error::NotAllowed].
+//! Disallowed one-way messages and connection requests, including their FDs, are dropped.
+//!
+//! ```
+//! use tokio::net::UnixStream;
+//!
+//! use krossbar_rpc::{
+//!     access::{AccessRequest, RequestKind},
+//!     rpc::Rpc,
+//! };
+//!
+//! async fn serve(stream: UnixStream) {
+//!     // Only root can call `reboot`
+//!     let mut rpc = Rpc::new(stream, "client").with_access_policy(|request: &AccessRequest| {
+//!         !matches!(request.kind, RequestKind::Call if request.endpoint == "reboot")
+//!             || request.credentials.is_some_and(|credentials| credentials.uid == 0)
+//!     });
+//!
+//!     while let Ok(request) = rpc.poll().await {
+//!         println!("Allowed request: {request:?}");
+//!     }
+//! }
+//! ```
+use crate::transport::PeerCredentials;
+
+/// Incoming request kind
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestKind<'a> {
+    /// One-way message
+    Message,
+    /// Method call
+    Call,
+    /// Subscription
+    Subscription,
+    /// Connection request from `client_name` to `target_name`
+    Connection {
+        client_name: &'a str,
+        target_name: &'a str,
+    },
+}
+
+/// Incoming request description to check
+#[derive(Clone, Copy, Debug)]
+pub struct AccessRequest<'a> {
+    pub kind: RequestKind<'a>,
+    /// Requested endpoint. Connection requests use `connect` endpoint
+    pub endpoint: &'a str,
+    /// Verbose peer name
+    pub peer_name: &'a str,
+    /// Peer process credentials. Available only for [tokio::net::UnixStream] connections
+    pub credentials: Option<PeerCredentials>,
+}
+
+/// Incoming requests authorization policy
+pub trait AccessPolicy: Send + Sync {
+    /// If the `request` is allowed to reach user code
+    fn is_allowed(&self, request: &AccessRequest) -> bool;
+}
+
+impl<F> AccessPolicy for F
+where
+    F: Fn(&AccessRequest) -> bool + Send + Sync,
+{
+    fn is_allowed(&self, request: &AccessRequest) -> bool {
+        self(request)
+    }
+}
@@ -1,78 +1,149 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
 
+use crate::{
+    fd::TypedFd,
+    message::{self},
+    subscription::SubscriptionOptions,
+    subscription_queue::{self, Push, QueueReceiver, QueueSender},
+};
 use bson::Bson;
-use futures::{
-    channel::{
-        mpsc::{channel, Receiver, Sender},
-        oneshot::{channel as one_channel, Receiver as OneReceiver, Sender as OneSender},
-    },
-    SinkExt,
+use futures::channel::oneshot::{
+    channel as one_channel, Receiver as OneReceiver, Sender as OneSender,
 };
 use log::{debug, info, trace, warn};
-use tokio::net::UnixStream;
 
-use crate::message::{self};
+/// Subscription response, which should be pushed into the subscription queue
+/// after the registry is unlocked
+pub struct SubscriptionDelivery {
+    pub id: i64,
+    sender: Arc<QueueSender>,
+    response: crate::Result<Bson>,
+}
 
-const BAD_RESPONSE_QUEUE_SIZE: usize = 100;
+impl SubscriptionDelivery {
+    pub(crate) fn push(self) -> Push {
+        self.sender.push(self.response)
+    }
+}
 
-/// A registry of calls, which can be added, resulting in a call id, and resolved after
-/// the client has responded
-pub struct CallsRegistry {
-    id_counter: i64,
+/// FD call response with received FDs
+type FdCallResult = crate::Result<(Bson, Vec<TypedFd>)>;
+
+const SHARDS_COUNT: usize = 16;
+
+/// Registry shard. Contains calls, which ids fall into the shard
+#[derive(Default)]
+struct Shard {
     /// Active calls
     calls: HashMap<i64, OneSender<crate::Result<Bson>>>,
     /// Active FD calls
-    fd_calls: HashMap<i64, OneSender<crate::Result<(Bson, UnixStream)>>>,
+    fd_calls: HashMap<i64, OneSender<FdCallResult>>,
     /// Current subscriptions
-    subscriptions: HashMap<i64, Sender<crate::Result<Bson>>>,
+    subscriptions: HashMap<i64, Arc<QueueSender>>,
     /// Persistent calls to send on reconnect
     active_subscriptions: HashMap<i64, message::RpcData>,
+    /// Pending calls to replay on reconnect
+    replay_calls: HashMap<i64, message::RpcData>,
+}
+
+/// A registry of calls, which can be added, resulting in a call id, and resolved after
+/// the client has responded.
+/// Calls are sharded by id, so concurrent calls and responses don't contend on a single lock.
+/// Shard locks are never held across `await`
+pub struct CallsRegistry {
+    id_counter: AtomicI64,
+    shards: Box<[Mutex<Shard>]>,
+    /// Connection reader has been dropped. New calls fail immediately
+    closed: AtomicBool,
+}
+
+impl Default for CallsRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CallsRegistry {
     pub fn new() -> Self {
         Self {
-            id_counter: 0,
-            calls: HashMap::new(),
-            fd_calls: HashMap::new(),
-            subscriptions: HashMap::new(),
-            active_subscriptions: HashMap::new(),
+            id_counter: AtomicI64::new(0),
+            shards: (0..SHARDS_COUNT)
+                .map(|_| Mutex::new(Shard::default()))
+                .collect(),
+            closed: AtomicBool::new(false),
         }
     }
 
-    pub fn add_persistent_call(&mut self, id: i64, data: message::RpcData) {
-        assert!(!self.active_subscriptions.contains_key(&id));
-
-        self.active_subscriptions.insert(id, data);
-    }
-
-    pub fn add_call(&mut self) -> (i64, OneReceiver<crate::Result<Bson>>) {
+    /// Add a call. If `replay_data` is set, the call is resent on reconnect
+    pub fn add_call(
+        &self,
+        replay_data: Option<message::RpcData>,
+    ) -> (i64, OneReceiver<crate::Result<Bson>>) {
         let (sender, receiver) = one_channel();
         let id = self.next_id();
 
-        self.calls.insert(id, sender);
+        let mut shard = self.shard(id);
+        // Dropping the sender fails the call
+        if self.is_closed() {
+            return (id, receiver);
+        }
+
+        shard.calls.insert(id, sender);
+        if let Some(data) = replay_data {
+            shard.replay_calls.insert(id, data);
+        }
 
         trace!("Add new call");
 
         (id, receiver)
     }
 
-    pub fn add_fd_call(&mut self) -> (i64, OneReceiver<crate::Result<(Bson, UnixStream)>>) {
+    /// Add an FD call. If `replay_data` is set, the call is resent on reconnect
+    pub fn add_fd_call(
+        &self,
+        replay_data: Option<message::RpcData>,
+    ) -> (i64, OneReceiver<FdCallResult>) {
         let (sender, receiver) = one_channel();
         let id = self.next_id();
 
-        self.fd_calls.insert(id, sender);
+        let mut shard = self.shard(id);
+        if self.is_closed() {
+            return (id, receiver);
+        }
+
+        shard.fd_calls.insert(id, sender);
+        if let Some(data) = replay_data {
+            shard.replay_calls.insert(id, data);
+        }
 
         trace!("Add new FD call");
 
         (id, receiver)
     }
 
-    pub fn add_subscription(&mut self) -> (i64, Receiver<crate::Result<Bson>>) {
-        let (sender, receiver) = channel(BAD_RESPONSE_QUEUE_SIZE);
+    /// Add a subscription. Subscription `data` is resent on reconnect
+    pub(crate) fn add_subscription(
+        &self,
+        options: &SubscriptionOptions,
+        data: message::RpcData,
+    ) -> (i64, QueueReceiver) {
+        let (sender, receiver) =
+            subscription_queue::queue(options.buffer_size, options.overflow_policy);
         let id = self.next_id();
 
-        self.subscriptions.insert(id, sender);
+        let mut shard = self.shard(id);
+        if self.is_closed() {
+            return (id, receiver);
+        }
+
+        shard.subscriptions.insert(id, Arc::new(sender));
+        shard.active_subscriptions.insert(id, data);
 
         trace!("Add new subscription");
 
@@ -80,95 +151,159 @@ impl CallsRegistry {
     }
 
     pub fn resolve_with_fd(
-        &mut self,
+        &self,
         message_id: i64,
         response: crate::Result<Bson>,
-        maybe_fd: Option<UnixStream>,
+        fds: Vec<TypedFd>,
     ) {
         debug!("Incoming fd response for {message_id}: {response:?}");
 
-        if let Some(channel) = self.fd_calls.remove(&message_id) {
-            match response {
-                Ok(doc) => {
-                    if let Some(stream) = maybe_fd {
-                        if channel.send(Ok((doc, stream))).is_err() {
-                            warn!("User wasn't waiting for an fd call response")
-                        } else {
-                            debug!("Succesfully resolved FD response for a message {message_id}")
-                        }
-                    } else {
-                        warn!("Ok response for a stream request w/o the stream itself");
-                        if channel
-                            .send(Err(crate::Error::InternalError(
-                                "Hub returned Ok response without a stream handle".into(),
-                            )))
-                            .is_err()
-                        {
-                            warn!("User wasn't waiting for an fd call response")
-                        }
-                    }
-                }
-                Err(e) => {
-                    if channel.send(Err(e)).is_err() {
-                        warn!("User wasn't waiting for an fd call response")
-                    }
-                }
+        let channel = {
+            let mut shard = self.shard(message_id);
+            shard.replay_calls.remove(&message_id);
+            shard.fd_calls.remove(&message_id)
+        };
+
+        if let Some(channel) = channel {
+            if channel.send(response.map(|doc| (doc, fds))).is_err() {
+                warn!("User wasn't waiting for an fd call response")
+            } else {
+                debug!("Succesfully resolved FD response for a message {message_id}")
             }
         } else {
             warn!("Received unexpected response. Call registry doesn't have matching request")
         }
     }
 
-    pub async fn resolve(&mut self, message_id: i64, response: crate::Result<Bson>) {
+    /// Resolve a call, or return subscription response delivery. The delivery should be pushed
+    /// outside of the registry to not block other calls if the subscriber is slow
+    pub fn resolve(
+        &self,
+        message_id: i64,
+        response: crate::Result<Bson>,
+    ) -> Option<SubscriptionDelivery> {
+        let mut shard = self.shard(message_id);
+
         // Try to resolve an active call
-        if let Some(channel) = self.calls.remove(&message_id) {
+        if let Some(channel) = shard.calls.remove(&message_id) {
+            shard.replay_calls.remove(&message_id);
+            drop(shard);
+
             if channel.send(response).is_err() {
                 warn!("User dropped call handle. Failed to send a response")
             } else {
                 debug!("Succesfully resolved {message_id} call")
             }
-        } else if let Some(channel) = self.subscriptions.get_mut(&message_id) {
+        } else if let Some(sender) = shard.subscriptions.get(&message_id) {
             // Subscription is no longer active
-            if !self.active_subscriptions.contains_key(&message_id) {
+            if !shard.active_subscriptions.contains_key(&message_id) {
                 debug!("Inactive subscription response")
-            // Try to resolve an active subscription
-            } else if channel.send(response).await.is_err() {
-                warn!("User dropped subscriptions handle. Failed to send a response");
-
-                // Remove subscription as no one is waiting for it anymore
-                self.active_subscriptions.remove(&message_id);
-            // No such call
+            // Deliver response to an active subscription
             } else {
-                debug!("Succesfully resolved {message_id} subscription")
+                return Some(SubscriptionDelivery {
+                    id: message_id,
+                    sender: sender.clone(),
+                    response,
+                });
             }
         // If we've received an error for FD call, we may not know it's actually FD response
         // Let's check it manually
-        } else if self.fd_calls.contains_key(&message_id) {
-            self.resolve_with_fd(message_id, response, None)
+        } else if shard.fd_calls.contains_key(&message_id) {
+            drop(shard);
+            self.resolve_with_fd(message_id, response, Vec::new())
         } else {
             warn!("Unexpected peer response: {:?}", response)
         }
+
+        None
+    }
+
+    /// Remove pending call, FD call or subscription. Used to forget calls, which are not going to be resolved
+    pub fn remove(&self, message_id: i64) {
+        let mut shard = self.shard(message_id);
+
+        if shard.calls.remove(&message_id).is_some()
+            || shard.fd_calls.remove(&message_id).is_some()
+            || shard.subscriptions.remove(&message_id).is_some()
+        {
+            debug!("Removed pending call {message_id}")
+        }
+
+        shard.active_subscriptions.remove(&message_id);
+        shard.replay_calls.remove(&message_id);
     }
 
-    pub fn clear_pending_calls(&mut self) {
+    /// Clear pending calls and FD calls, which are not going to be replayed.
+    /// Dropping call senders fails the calls with [crate::Error::PeerDisconnected]
+    pub fn clear_pending_calls(&self) {
         info!("Clearing calls queue");
-        self.calls.clear()
+
+        for shard in self.shards.iter() {
+            let shard = &mut *shard.lock().unwrap();
+
+            let replay_calls = &shard.replay_calls;
+            shard.calls.retain(|id, _| replay_calls.contains_key(id));
+            shard.fd_calls.retain(|id, _| replay_calls.contains_key(id));
+        }
     }
 
-    pub fn active_subscriptions(&self) -> impl Iterator<Item = (&i64, &message::RpcData)> {
-        self.active_subscriptions.iter()
+    /// Fail all pending calls, FD calls, and subscriptions, because the connection reader
+    /// is gone and nothing is going to resolve them. Calls made after are failed immediately.
+    /// Dropping call senders fails the calls with [crate::Error::PeerDisconnected].
+    /// Dropping subscription senders ends the subscription streams
+    pub fn close(&self) {
+        info!("Closing calls registry");
+
+        // Checked under a shard lock when a call is added, so the call is either
+        // cleared below, or never added
+        self.closed.store(true, Ordering::SeqCst);
+
+        for shard in self.shards.iter() {
+            let cleared = std::mem::take(&mut *shard.lock().unwrap());
+            drop(cleared);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Active subscriptions and calls to replay, which should be resent on reconnect
+    pub fn resend_calls(&self) -> Vec<(i64, message::RpcData)> {
+        let mut result: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap();
+
+                shard
+                    .active_subscriptions
+                    .iter()
+                    .chain(shard.replay_calls.iter())
+                    .map(|(id, data)| (*id, data.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        // Keep the original calls order
+        result.sort_by_key(|(id, _)| *id);
+        result
     }
 
-    fn next_id(&mut self) -> i64 {
-        self.id_counter += 1;
-        self.id_counter
+    fn shard(&self, id: i64) -> MutexGuard<'_, Shard> {
+        self.shards[id.rem_euclid(SHARDS_COUNT as i64) as usize]
+            .lock()
+            .unwrap()
+    }
+
+    fn next_id(&self) -> i64 {
+        self.id_counter.fetch_add(1, Ordering::Relaxed) + 1
     }
 }
 
 #[cfg(test)]
 mod tests {
     use bson::Bson;
-    use futures::lock::Mutex;
     use std::{sync::Arc, time::Instant};
     use tokio::task::JoinSet;
 
@@ -178,15 +313,16 @@ mod tests {
         const NUM_OPS: i32 = 10000;
 
         let mut set = JoinSet::new();
-        let registry = Arc::new(Mutex::new(super::CallsRegistry::new()));
+        let registry = Arc::new(super::CallsRegistry::new());
         let now = Instant::now();
 
         for _ in 0..NUM_THREAD {
             let reg_copy = registry.clone();
             set.spawn(async move {
                 for _ in 0..NUM_OPS {
-                    let call = reg_copy.lock().await.add_call();
-                    let _ = reg_copy.lock().await.resolve(call.0, Ok(Bson::default()));
+                    let (id, receiver) = reg_copy.add_call(None);
+                    reg_copy.resolve(id, Ok(Bson::default()));
+                    assert!(receiver.await.unwrap().is_ok());
                 }
             });
         }
@@ -194,4 +330,17 @@ mod tests {
         set.join_all().await;
         println!("Fuzzing registry took {} ms", now.elapsed().as_millis());
     }
+
+    #[tokio::test]
+    async fn test_close() {
+        let registry = super::CallsRegistry::new();
+
+        let (_, call) = registry.add_call(Some(crate::message::RpcData::Cancel));
+        registry.close();
+        assert!(call.await.is_err());
+
+        // Calls made after closing fail immediately
+        let (_, call) = registry.add_call(None);
+        assert!(call.await.is_err());
+    }
 }
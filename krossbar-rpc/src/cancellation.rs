@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use log::{debug, trace};
+use tokio::sync::watch::{channel, Receiver, Sender};
+
+struct PendingRequest {
+    /// Unique request key. Used to distinguish requests with the same message id
+    /// from different connections
+    key: u64,
+    /// Cancellation sender
+    sender: Sender<bool>,
+}
+
+#[derive(Default)]
+struct Inner {
+    key_counter: u64,
+    requests: HashMap<i64, PendingRequest>,
+}
+
+/// A registry of incoming requests, which can be cancelled by the peer
+#[derive(Clone, Default)]
+pub(crate) struct CancellationRegistry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CancellationRegistry {
+    /// Add new incoming request. Returned token is notified if the peer cancels the request
+    pub fn add(&self, message_id: i64) -> Cancellation {
+        let (sender, receiver) = channel(false);
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.key_counter += 1;
+        let key = inner.key_counter;
+
+        inner
+            .requests
+            .insert(message_id, PendingRequest { key, sender });
+
+        trace!("Add new cancellable request {message_id}");
+
+        Cancellation {
+            message_id,
+            key,
+            registry: Some(self.clone()),
+            receiver: Some(receiver),
+        }
+    }
+
+    /// Cancel incoming request by its message id
+    pub fn cancel(&self, message_id: i64) {
+        if let Some(request) = self.inner.lock().unwrap().requests.remove(&message_id) {
+            debug!("Peer cancelled request {message_id}");
+
+            let _ = request.sender.send(true);
+        } else {
+            debug!("Peer cancelled unknown or finished request {message_id}")
+        }
+    }
+
+    /// Drop all pending requests. All requests are considered cancelled
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().requests.clear()
+    }
+
+    fn remove(&self, message_id: i64, key: u64) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner
+            .requests
+            .get(&message_id)
+            .is_some_and(|request| request.key == key)
+        {
+            inner.requests.remove(&message_id);
+        }
+    }
+}
+
+/// Incoming request cancellation token.
+/// Removes the request from the registry when dropped
+pub(crate) struct Cancellation {
+    message_id: i64,
+    key: u64,
+    registry: Option<CancellationRegistry>,
+    /// Cancellation receiver. `None` for the requests, which can't be cancelled
+    receiver: Option<Receiver<bool>>,
+}
+
+impl Cancellation {
+    /// A token, which is never cancelled by the peer. Used for the requests, which can't be cancelled
+    pub fn never() -> Self {
+        Self {
+            message_id: -1,
+            key: 0,
+            registry: None,
+            receiver: None,
+        }
+    }
+
+    /// Check if the request was cancelled, or the peer disconnected
+    pub fn is_cancelled(&self) -> bool {
+        match &self.receiver {
+            Some(receiver) => *receiver.borrow() || receiver.has_changed().is_err(),
+            None => false,
+        }
+    }
+
+    /// Wait until the request is cancelled, or the peer disconnected
+    pub async fn cancelled(&self) {
+        match &self.receiver {
+            Some(receiver) => {
+                let _ = receiver.clone().wait_for(|cancelled| *cancelled).await;
+            }
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.take() {
+            registry.remove(self.message_id, self.key)
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::{fmt::Display, io::Write};
+
+use bson::Document;
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+
+use crate::Disconnect;
+
+/// Frame header size of the non-BSON codecs: length prefix and a codec tag
+const HEADER_SIZE: usize = 5;
+
+const MESSAGE_PACK_TAG: u8 = 0x81;
+const CBOR_TAG: u8 = 0x82;
+
+/// Destination of an encoded frame
+pub(crate) trait FrameWrite: Write {
+    /// Number of bytes written
+    fn position(&self) -> usize;
+
+    /// Overwrite already written bytes starting at `offset`
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> std::io::Result<()>;
+
+    /// Write a frame serialized into a separate buffer
+    fn put_frame(&mut self, frame: Vec<u8>) -> std::io::Result<()> {
+        self.write_all(&frame)
+    }
+}
+
+impl FrameWrite for Vec<u8> {
+    fn position(&self) -> usize {
+        self.len()
+    }
+
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> std::io::Result<()> {
+        self[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Take the buffer instead of copying it if nothing has been written yet
+    fn put_frame(&mut self, frame: Vec<u8>) -> std::io::Result<()> {
+        if self.is_empty() {
+            *self = frame;
+        } else {
+            self.extend_from_slice(&frame);
+        }
+
+        Ok(())
+    }
+}
+
+/// Message serialization format. Selected per connection using [crate::rpc::Rpc::with_codec].
+/// Both peers must use the same codec. Receiving a message encoded with another codec
+/// closes the connection with [Disconnect::CodecMismatch].
+///
+/// Every frame starts with a 4-byte little endian length prefix, which includes the prefix itself.
+/// BSON frames are plain BSON documents, so they're compatible with the peers, which don't support
+/// other codecs. Other codecs prepend the payload with a codec tag byte, which is never a valid
+/// BSON element type
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    #[default]
+    Bson,
+    MessagePack,
+    Cbor,
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bson => write!(f, "BSON"),
+            Self::MessagePack => write!(f, "MessagePack"),
+            Self::Cbor => write!(f, "CBOR"),
+        }
+    }
+}
+
+impl Codec {
+    /// Detect codec using the first byte after the length prefix
+    fn detect(tag: u8) -> Option<Self> {
+        match tag {
+            MESSAGE_PACK_TAG => Some(Self::MessagePack),
+            CBOR_TAG => Some(Self::Cbor),
+            // BSON element types, or the end of an empty document
+            0x00..=0x13 | 0x7F | 0xFF => Some(Self::Bson),
+            _ => None,
+        }
+    }
+
+    /// Serialize a message into a frame ready to be written into a stream
+    pub(crate) fn encode<T: Serialize>(&self, message: &T) -> crate::Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        self.encode_into(message, &mut frame)?;
+
+        Ok(frame)
+    }
+
+    /// Serialize a message into a `frame`, reusing its allocation.
+    /// BSON serializer can't write into an existing buffer, so BSON frames are always allocated
+    pub(crate) fn encode_into<T: Serialize>(
+        &self,
+        message: &T,
+        frame: &mut Vec<u8>,
+    ) -> crate::Result<()> {
+        frame.clear();
+        self.encode_to(message, frame)
+    }
+
+    /// Serialize a message into a `writer`, which is expected to be empty
+    pub(crate) fn encode_to<T: Serialize, W: FrameWrite>(
+        &self,
+        message: &T,
+        writer: &mut W,
+    ) -> crate::Result<()> {
+        match self {
+            // `Document::to_writer` serializes through `bson::to_vec` as well, and making
+            // an intermediate document is an order of magnitude slower
+            Self::Bson => {
+                let frame = bson::to_vec(message)
+                    .map_err(|e| crate::Error::InternalError(e.to_string()))?;
+                writer
+                    .put_frame(frame)
+                    .map_err(|e| crate::Error::InternalError(e.to_string()))
+            }
+            Self::MessagePack => Self::encode_tagged(MESSAGE_PACK_TAG, writer, |writer| {
+                rmp_serde::encode::write(writer, message).map_err(|e| e.to_string())
+            }),
+            Self::Cbor => Self::encode_tagged(CBOR_TAG, writer, |writer| {
+                ciborium::into_writer(message, writer).map_err(|e| e.to_string())
+            }),
+        }
+    }
+
+    /// Write a length prefix and a codec `tag` followed by a payload into an empty `frame`
+    fn encode_tagged<W: FrameWrite>(
+        tag: u8,
+        writer: &mut W,
+        write_payload: impl FnOnce(&mut W) -> Result<(), String>,
+    ) -> crate::Result<()> {
+        writer
+            .write_all(&[0, 0, 0, 0, tag])
+            .map_err(|e| crate::Error::InternalError(e.to_string()))?;
+        write_payload(writer).map_err(crate::Error::InternalError)?;
+
+        let len = i32::try_from(writer.position())
+            .map_err(|_| crate::Error::InternalError("Message is too large".to_owned()))?;
+        writer
+            .write_at(0, &len.to_le_bytes())
+            .map_err(|e| crate::Error::InternalError(e.to_string()))
+    }
+
+    /// Deserialize a message from a `frame`, starting from the length prefix
+    pub(crate) fn decode<T: DeserializeOwned>(&self, frame: &[u8]) -> Result<T, Disconnect> {
+        let Some(&tag) = frame.get(HEADER_SIZE - 1) else {
+            return Err(Disconnect::MalformedFrame("Frame is too short".to_owned()));
+        };
+
+        match Self::detect(tag) {
+            Some(received) if received != *self => {
+                return Err(Disconnect::CodecMismatch {
+                    expected: *self,
+                    received,
+                })
+            }
+            Some(_) => {}
+            None => {
+                return Err(Disconnect::MalformedFrame(format!(
+                    "Unknown codec tag {tag:#x}"
+                )))
+            }
+        }
+
+        let payload = &frame[HEADER_SIZE..];
+
+        // On error check if the data is valid, but is not a known message.
+        // This is slow, but happens only once before closing the connection
+        match self {
+            Self::Bson => bson::from_slice(frame).map_err(|e| match Document::from_reader(frame) {
+                Ok(_) => Disconnect::UnknownMessage(e.to_string()),
+                Err(e) => Disconnect::MalformedFrame(e.to_string()),
+            }),
+            Self::MessagePack => rmp_serde::from_slice(payload).map_err(|e| {
+                match rmp_serde::from_slice::<IgnoredAny>(payload) {
+                    Ok(_) => Disconnect::UnknownMessage(e.to_string()),
+                    Err(e) => Disconnect::MalformedFrame(e.to_string()),
+                }
+            }),
+            Self::Cbor => ciborium::from_reader(payload).map_err(|e| {
+                match ciborium::from_reader::<IgnoredAny, _>(payload) {
+                    Ok(_) => Disconnect::UnknownMessage(e.to_string()),
+                    Err(e) => Disconnect::MalformedFrame(e.to_string()),
+                }
+            }),
+        }
+    }
+}
@@ -32,6 +32,77 @@ pub enum Error {
     /// Client error
     #[error("Client returned an error: {0}")]
     ClientError(String),
+    /// Operation is not supported by the connection transport, e.g. FD passing over TCP
+    #[error("Operation is not supported by the transport")]
+    NotSupported,
+    /// Peer didn't respond to a call in time
+    #[error("Call timed out")]
+    Timeout,
+    /// Subscription buffer overflowed. Used with [crate::subscription::OverflowPolicy::Disconnect]
+    #[error("Subscription buffer overflowed")]
+    SubscriptionOverflow,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Reason why [crate::rpc::Rpc] stopped polling the connection
+#[derive(Debug, Error)]
+pub enum Disconnect {
+    /// Peer closed the connection
+    #[error("Peer closed the connection")]
+    Closed,
+    /// Connection I/O error
+    #[error("Connection I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Peer sent a frame, which can't be decoded, e.g. invalid length or BSON.
+    /// The connection is closed after the error
+    #[error("Malformed frame: {0}")]
+    MalformedFrame(String),
+    /// Peer sent a valid frame, which is not a known message. Usually means protocol version mismatch.
+    /// The connection is closed after the error
+    #[error("Unknown message: {0}")]
+    UnknownMessage(String),
+    /// Peer sent a message encoded with another codec. See [crate::codec::Codec].
+    /// The connection is closed after the error
+    #[error("Codec mismatch. Expected {expected}, received {received}")]
+    CodecMismatch {
+        expected: crate::codec::Codec,
+        received: crate::codec::Codec,
+    },
+}
+
+impl Disconnect {
+    /// If the peer violated the protocol
+    pub fn is_protocol_violation(&self) -> bool {
+        matches!(
+            self,
+            Self::MalformedFrame(_) | Self::UnknownMessage(_) | Self::CodecMismatch { .. }
+        )
+    }
+}
+
+/// Reason why [crate::rpc::Rpc::handshake] failed
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    /// Connection failed before the handshake completed
+    #[error("Connection failed during handshake: {0}")]
+    Disconnected(#[from] Disconnect),
+    /// Peer didn't send its hello in time
+    #[error("Handshake timed out")]
+    Timeout,
+    /// Peer sent a message instead of a hello. Usually means the peer doesn't support handshake
+    #[error("Peer doesn't support handshake")]
+    NotSupported,
+    /// Peer protocol version is incompatible with the local one
+    #[error("Incompatible protocol version. Local version: {local}, peer version: {peer}")]
+    IncompatibleVersion { local: u32, peer: u32 },
+    /// Peer uses another codec
+    #[error("Codec mismatch. Local codec: {local}, peer codec: {peer}")]
+    CodecMismatch {
+        local: crate::codec::Codec,
+        peer: crate::codec::Codec,
+    },
+    /// Connection doesn't support features required by [crate::handshake::HandshakeOptions::require]
+    #[error("Connection doesn't support required features: {0:?}")]
+    MissingFeatures(crate::handshake::Features),
+}
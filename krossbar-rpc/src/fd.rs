@@ -0,0 +1,103 @@
+//! File descriptors, which can be passed over FD passing capable transports.
+//!
+//! Connection requests and FD call responses carry a list of [TypedFd].
+//! Each descriptor is sent along with its [FdKind], so the receiver knows how to use it.
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Result},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::net::UnixStream as StdUnixStream,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixStream;
+
+use crate::transport::PeerCredentials;
+
+/// Kind of a passed file descriptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FdKind {
+    /// Unix socket. Can be converted into [tokio::net::UnixStream]
+    UnixStream,
+    /// Anonymous memory file, e.g. a shared memory buffer
+    Memfd,
+    /// Event notification descriptor
+    EventFd,
+    /// Pipe end
+    Pipe,
+    /// Regular file
+    File,
+    /// Any other descriptor
+    Other,
+}
+
+/// Owned file descriptor with its kind
+#[derive(Debug)]
+pub struct TypedFd {
+    kind: FdKind,
+    fd: OwnedFd,
+}
+
+impl TypedFd {
+    pub fn new(kind: FdKind, fd: impl Into<OwnedFd>) -> Self {
+        Self {
+            kind,
+            fd: fd.into(),
+        }
+    }
+
+    pub fn kind(&self) -> FdKind {
+        self.kind
+    }
+
+    pub fn into_fd(self) -> OwnedFd {
+        self.fd
+    }
+
+    /// Convert into a Tokio stream. Fails if the descriptor isn't an [FdKind::UnixStream].
+    /// Must be called within Tokio runtime
+    pub fn into_unix_stream(self) -> Result<UnixStream> {
+        if self.kind != FdKind::UnixStream {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Can't convert {:?} descriptor into a stream", self.kind),
+            ));
+        }
+
+        let stream = StdUnixStream::from(self.fd);
+        stream.set_nonblocking(true)?;
+
+        UnixStream::from_std(stream)
+    }
+
+    /// Credentials of the process on the other side of an [FdKind::UnixStream] descriptor
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        if self.kind != FdKind::UnixStream {
+            return None;
+        }
+
+        PeerCredentials::from_fd(self.fd.as_fd())
+    }
+}
+
+impl AsFd for TypedFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl TryFrom<UnixStream> for TypedFd {
+    type Error = Error;
+
+    fn try_from(stream: UnixStream) -> Result<Self> {
+        Ok(Self::new(FdKind::UnixStream, stream.into_std()?))
+    }
+}
+
+impl From<File> for TypedFd {
+    fn from(file: File) -> Self {
+        Self::new(FdKind::File, file)
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{codec::Codec, HandshakeError};
+
+/// Current protocol version, sent in a handshake
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Min protocol version of a peer, which this version can talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+/// Default handshake timeout: 5s
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Hello frame magic value
+const HELLO_MAGIC: &str = "krossbar";
+
+/// Optional protocol features
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Features {
+    /// Connection transport supports FD passing
+    pub fd_passing: bool,
+    /// Peer handles call cancellation and unsubscription messages
+    pub cancellation: bool,
+    /// Peer supports compressed payloads
+    pub compression: bool,
+    /// Peer is able to receive large messages using shared memory
+    pub shared_memory: bool,
+}
+
+impl Features {
+    /// Features supported by both `self` and `other`
+    pub fn intersection(&self, other: &Features) -> Features {
+        Features {
+            fd_passing: self.fd_passing && other.fd_passing,
+            cancellation: self.cancellation && other.cancellation,
+            compression: self.compression && other.compression,
+            shared_memory: self.shared_memory && other.shared_memory,
+        }
+    }
+
+    /// Features of `self`, which are missing in `other`
+    pub fn difference(&self, other: &Features) -> Features {
+        Features {
+            fd_passing: self.fd_passing && !other.fd_passing,
+            cancellation: self.cancellation && !other.cancellation,
+            compression: self.compression && !other.compression,
+            shared_memory: self.shared_memory && !other.shared_memory,
+        }
+    }
+
+    /// If no features are set
+    pub fn is_empty(&self) -> bool {
+        *self == Features::default()
+    }
+}
+
+/// Handshake parameters. See [crate::rpc::Rpc::handshake]
+#[derive(Clone, Debug)]
+pub struct HandshakeOptions {
+    pub(crate) name: String,
+    pub(crate) codec: Codec,
+    pub(crate) required_features: Features,
+    pub(crate) timeout: Duration,
+}
+
+impl HandshakeOptions {
+    /// Make handshake options with a verbose local `name`, which is sent to the peer
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            codec: Codec::default(),
+            required_features: Features::default(),
+            timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Messages codec. Peer must use the same codec. Default is [Codec::Bson]
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Fail the handshake with [HandshakeError::MissingFeatures] if the connection doesn't support
+    /// any of the `features`. Default is none
+    pub fn require(mut self, features: Features) -> Self {
+        self.required_features = features;
+        self
+    }
+
+    /// Fail the handshake with [HandshakeError::Timeout] if the peer doesn't send its hello
+    /// within `timeout`. Default is [DEFAULT_HANDSHAKE_TIMEOUT]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Peer parameters received in a handshake
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Verbose peer name
+    pub name: String,
+    /// Peer protocol version
+    pub protocol_version: u32,
+    /// Features supported by both peers
+    pub features: Features,
+}
+
+/// Handshake frame. Always encoded with [Codec::Bson], so peers can detect codec mismatch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Hello {
+    hello: String,
+    version: u32,
+    min_version: u32,
+    codec: Codec,
+    features: Features,
+    name: String,
+}
+
+impl Hello {
+    pub fn new(options: &HandshakeOptions, fd_passing: bool) -> Self {
+        Self {
+            hello: HELLO_MAGIC.to_owned(),
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            codec: options.codec,
+            features: Features {
+                fd_passing,
+                cancellation: true,
+                compression: false,
+                shared_memory: fd_passing,
+            },
+            name: options.name.clone(),
+        }
+    }
+
+    /// Check if the `peer` is compatible with the local side.
+    /// Returns negotiated peer parameters
+    pub fn negotiate(
+        &self,
+        peer: Hello,
+        options: &HandshakeOptions,
+    ) -> Result<PeerInfo, HandshakeError> {
+        if peer.hello != HELLO_MAGIC {
+            return Err(HandshakeError::NotSupported);
+        }
+
+        if peer.version < self.min_version || self.version < peer.min_version {
+            return Err(HandshakeError::IncompatibleVersion {
+                local: self.version,
+                peer: peer.version,
+            });
+        }
+
+        if peer.codec != self.codec {
+            return Err(HandshakeError::CodecMismatch {
+                local: self.codec,
+                peer: peer.codec,
+            });
+        }
+
+        let features = self.features.intersection(&peer.features);
+
+        let missing = options.required_features.difference(&features);
+        if !missing.is_empty() {
+            return Err(HandshakeError::MissingFeatures(missing));
+        }
+
+        Ok(PeerInfo {
+            name: peer.name,
+            protocol_version: peer.version,
+            features,
+        })
+    }
+}
@@ -2,10 +2,20 @@
 RPC library used by Krossbar platform for communication.
 
 The library:
-- Receives [tokio::net::UnixStream] and returns RPC handle;
-- Allows making calls, subscribing to an endpoint, and sending [tokio::net::UnixStream] using RPC connection;
+- Receives [tokio::net::UnixStream], or any other [transport::Transport] and returns RPC handle;
+- Allows making calls, subscribing to an endpoint, and passing file descriptors ([fd::TypedFd]) using RPC connection;
 - Supports replacing the stream after reconnection, resubscribing to the active subscriptions, and keeping all client handles valid;
+- Allows watching connection state via [writer::RpcWriter::watch_connection_state];
+- Supports batching outgoing messages via [rpc::Rpc::with_write_batching];
+- Optionally writes outgoing messages from a dedicated task via [rpc::Rpc::with_writer_task];
+- Supports BSON, MessagePack, and CBOR message encoding via [codec::Codec];
+- Supports protocol version and features negotiation via [rpc::Rpc::handshake];
+- Exposes peer process credentials via [rpc::Rpc::peer_credentials] and [request::RpcRequest::peer_credentials];
+- Allows authorizing incoming requests via [access::AccessPolicy];
+- Transparently sends large messages using sealed shared memory via [rpc::Rpc::with_shared_memory];
 - Supports message exchange monitoring via [Monitor]
+- Provides [router::Router] to route incoming requests to the endpoint handlers
+- Provides [reconnect::ReconnectingRpc] to automatically reconnect to a peer socket
 
 Use [rpc::Rpc::poll] method to poll the stream. This includes waiting for a call or subscriptions response.
 
@@ -84,14 +94,13 @@ async fn poll() {
     let mut rpc = Rpc::new(stream, "hub");
 
     loop {
-        let request = rpc.poll().await;
-
-        if request.is_none() {
-            println!("Client disconnected");
-            return;
-        }
-
-        let mut request = request.unwrap();
+        let mut request = match rpc.poll().await {
+            Ok(request) => request,
+            Err(reason) => {
+                println!("Client disconnected: {reason}");
+                return;
+            }
+        };
 
         println!("Incoming method call: {}", request.endpoint());
         match request.take_body().unwrap() {
@@ -104,9 +113,11 @@ async fn poll() {
             },
             Body::Subscription => {
                 println!("Incoming subscription");
-                request.respond(Ok(41)).await;
-                request.respond(Ok(42)).await;
-                request.respond(Ok(43)).await;
+                let sink = request.into_subscription();
+                sink.send(41).await;
+                sink.send(42).await;
+                sink.send(43).await;
+                sink.close().await;
             },
             Body::Fd { client_name, .. } => {
                 println!("Incoming connection request from {client_name}");
@@ -120,18 +131,39 @@ async fn poll() {
 See `tests/` for more examples.
 */
 
+pub mod access;
 mod calls_registry;
+mod cancellation;
+pub mod codec;
 mod error;
+pub mod fd;
+pub mod handshake;
 mod message;
 mod message_stream;
 #[cfg(feature = "monitor")]
 pub mod monitor;
+pub mod reconnect;
 pub mod request;
+pub mod router;
 pub mod rpc;
+mod shm;
+mod socket_writer;
+pub mod subscription;
+mod subscription_queue;
+pub mod transport;
+mod write_queue;
 pub mod writer;
 
+pub use bson;
 pub use error::*;
-
+// Used by the code generated by `krossbar-rpc-macros`
+#[doc(hidden)]
+pub use tokio;
+
+// Used by the registry benchmark. Not a part of the public API
+#[cfg(feature = "impl-bench")]
+#[doc(hidden)]
+pub use calls_registry::CallsRegistry;
 #[cfg(feature = "impl-monitor")]
 pub use message::*;
 #[cfg(feature = "impl-monitor")]
@@ -1,6 +1,8 @@
 use bson::Bson;
 use serde::{Deserialize, Serialize};
 
+use crate::fd::FdKind;
+
 /// RPC message
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RpcMessage {
@@ -21,12 +23,27 @@ pub enum RpcData {
     /// `client_name` - initiator client name
     /// `target_name` - connection target name, which can be used
     ///     to implement gateways
+    /// `fds` - kinds of the file descriptors, which follow the message
     ConnectionRequest {
         client_name: String,
         target_name: String,
+        fds: Vec<FdKind>,
     },
     /// Message response
     Response(crate::Result<Bson>),
-    /// Mesage response, which precedes incoming FD
-    FdResponse(crate::Result<Bson>),
+    /// Mesage response, which precedes incoming FDs
+    /// `fds` - kinds of the file descriptors, which follow the message
+    FdResponse {
+        result: crate::Result<Bson>,
+        fds: Vec<FdKind>,
+    },
+    /// Call cancellation. Message id is the id of the call to cancel
+    Cancel,
+    /// Subscription cancellation. Message id is the id of the subscription
+    Unsubscribe,
+    /// Subscription stream end. Message id is the id of the subscription
+    EndOfStream,
+    /// Large message, which is placed into a sealed memfd following the message.
+    /// `size` - size of the encoded message in the memfd
+    SharedMemory { size: u32 },
 }
@@ -1,18 +1,27 @@
-use std::io::Cursor;
+use std::io::ErrorKind;
 
-use bson::Document;
 use log::trace;
-use serde::{de::DeserializeOwned, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde::de::DeserializeOwned;
+use tokio::io::AsyncReadExt;
+
+use crate::{codec::Codec, Disconnect};
+
+/// Minimal frame size: length prefix and a trailing zero of a BSON document, or a codec tag
+const MIN_MESSAGE_SIZE: usize = 5;
+/// Max read buffer capacity kept between messages. Larger buffers are shrunk after use
+const MAX_RETAINED_BUFFER_SIZE: usize = 64 * 1024;
 
 /// A trait which can read [serde::de::DeserializeOwned] from a stream
 pub trait AsyncReadMessage<T: DeserializeOwned> {
-    async fn read_message(&mut self) -> crate::Result<T>;
-}
-
-/// A trait which can write [serde::ser::Serialize] into a stream
-pub trait AsyncWriteMessage<T: Serialize> {
-    async fn write_message(&mut self, message: &T) -> crate::Result<()>;
+    /// Read a message encoded with `codec` using `buffer` to store message data.
+    /// The buffer is reused between messages to avoid allocations.
+    /// Returns [Disconnect::MalformedFrame] if message length is invalid or exceeds `max_size`
+    async fn read_message(
+        &mut self,
+        buffer: &mut Vec<u8>,
+        max_size: usize,
+        codec: Codec,
+    ) -> Result<T, Disconnect>;
 }
 
 impl<R, T> AsyncReadMessage<T> for R
@@ -20,48 +29,86 @@ where
     R: AsyncReadExt + Unpin,
     T: DeserializeOwned,
 {
-    async fn read_message(&mut self) -> crate::Result<T> {
-        // Read BSON len
+    async fn read_message(
+        &mut self,
+        buffer: &mut Vec<u8>,
+        max_size: usize,
+        codec: Codec,
+    ) -> Result<T, Disconnect> {
+        // Read frame len
         let mut len_buf = [0u8; 4];
 
-        self.read_exact(&mut len_buf)
-            .await
-            .map_err(|_| crate::Error::PeerDisconnected)?;
+        self.read_exact(&mut len_buf).await.map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                Disconnect::Closed
+            } else {
+                Disconnect::Io(e)
+            }
+        })?;
 
         let len = i32::from_le_bytes(len_buf);
-        trace!("BSON message len: {:?}", len);
+        trace!("Message len: {:?}", len);
 
-        // Read BSON body. Prepend BSON len to the rest of the data
-        let mut data: Vec<u8> = len_buf.into();
-        self.take((len - 4) as u64)
-            .read_to_end(&mut data)
-            .await
-            .map_err(|_| crate::Error::PeerDisconnected)?;
+        let len = match usize::try_from(len) {
+            Ok(len) if (MIN_MESSAGE_SIZE..=max_size).contains(&len) => len,
+            _ => {
+                return Err(Disconnect::MalformedFrame(format!(
+                    "Invalid message length {len}. Max message size: {max_size}"
+                )))
+            }
+        };
 
-        let mut cursor = Cursor::new(data);
-        let doc = Document::from_reader(&mut cursor)
-            .map_err(|e| crate::Error::InternalError(e.to_string()))?;
+        // Read message body. Prepend frame len to the rest of the data
+        buffer.clear();
+        buffer.resize(len, 0);
+        buffer[..4].copy_from_slice(&len_buf);
 
-        Ok(bson::from_document(doc).map_err(|e| crate::Error::InternalError(e.to_string()))?)
+        let result = match self.read_exact(&mut buffer[4..]).await {
+            Ok(_) => codec.decode(buffer),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(Disconnect::MalformedFrame(
+                format!("Truncated message of length {len}"),
+            )),
+            Err(e) => Err(Disconnect::Io(e)),
+        };
+
+        if buffer.capacity() > MAX_RETAINED_BUFFER_SIZE {
+            buffer.clear();
+            buffer.shrink_to(MAX_RETAINED_BUFFER_SIZE);
+        }
+
+        result
     }
 }
 
-impl<W, T> AsyncWriteMessage<T> for W
-where
-    W: AsyncWriteExt + Unpin,
-    T: Serialize,
-{
-    async fn write_message(&mut self, message: &T) -> crate::Result<()> {
-        let doc =
-            bson::to_document(message).map_err(|e| crate::Error::InternalError(e.to_string()))?;
-
-        let mut buffer: Vec<u8> = Vec::new();
-        doc.to_writer(&mut buffer)
-            .map_err(|e| crate::Error::InternalError(e.to_string()))?;
-
-        self.write_all(&buffer)
-            .await
-            .map_err(|_| crate::Error::PeerDisconnected)?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use bson::{doc, Document};
+    use tokio::io::AsyncWriteExt;
+
+    use super::{AsyncReadMessage, MAX_RETAINED_BUFFER_SIZE};
+    use crate::codec::Codec;
+
+    #[tokio::test]
+    async fn test_buffer_reuse() {
+        let (mut writer, mut reader) = tokio::io::duplex(1024 * 1024);
+        let mut buffer = Vec::new();
+
+        for size in [1024, 256 * 1024, 16] {
+            let message = doc! { "data": "x".repeat(size) };
+            writer
+                .write_all(&Codec::Bson.encode(&message).unwrap())
+                .await
+                .unwrap();
+
+            let received: Document = reader
+                .read_message(&mut buffer, usize::MAX, Codec::Bson)
+                .await
+                .unwrap();
+            assert_eq!(received, message);
+
+            // Buffer is kept for the next message, but isn't larger than the limit
+            assert!(buffer.capacity() > size.min(MAX_RETAINED_BUFFER_SIZE / 2));
+            assert!(buffer.capacity() <= MAX_RETAINED_BUFFER_SIZE);
+        }
     }
 }
@@ -52,7 +52,11 @@ impl Monitor {
         };
 
         if let Some(rpc) = MONITOR_HANDLE.lock().await.as_ref() {
-            if let Err(_) = rpc.send_message(MESSAGE_METHOD, &monitor_message).await {
+            if rpc
+                .send_message(MESSAGE_METHOD, &monitor_message)
+                .await
+                .is_err()
+            {
                 MONITOR_ACTIVE.store(false, Ordering::Relaxed);
 
                 debug!("Monitor disconnected");
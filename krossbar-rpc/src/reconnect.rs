@@ -0,0 +1,296 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    ops::Deref,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, info, warn};
+use tokio::net::UnixStream;
+
+use crate::{
+    handshake::HandshakeOptions, request::RpcRequest, rpc::Rpc, writer::RpcWriter, Disconnect,
+};
+
+/// Reconnection backoff parameters
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delay before the first reconnection attempt. Default is 100ms
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Max delay between reconnection attempts. Default is 10s
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Delay multiplier applied after every failed attempt. Default is 2
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Random part of the delay in range [0, 1]. A delay is randomly reduced by up to `jitter`
+    /// fraction to avoid clients reconnecting simultaneously. Default is 0.2
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Max number of reconnection attempts before giving up. Default is unlimited
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Delay before the `attempt`, starting from 1
+    fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+
+        Duration::from_secs_f64(delay * (1.0 - self.jitter * random_fraction()))
+    }
+}
+
+/// Random value in range [0, 1). Good enough for a jitter without pulling an RNG dependency
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(time.as_nanos());
+    }
+
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// RPC handle, which owns a socket path and reconnects to the peer if the connection is lost.
+/// Calls [Rpc::on_reconnected] after reconnection, so active subscriptions are resent,
+/// and all writer handles stay valid.
+/// Reconnection progress is reported using [crate::writer::ConnectionState::Reconnecting].
+/// The state is set to [crate::writer::ConnectionState::Disconnected] if reconnection
+/// attempts are exhausted.
+///
+/// ```
+/// use krossbar_rpc::reconnect::{Backoff, ReconnectingRpc};
+///
+/// async fn connect() {
+///     let mut rpc = ReconnectingRpc::connect("/tmp/hub.sock", "hub", Backoff::new())
+///         .await
+///         .unwrap();
+///
+///     let mut state = rpc.watch_connection_state();
+///     tokio::spawn(async move {
+///         while state.changed().await.is_ok() {
+///             println!("Connection state: {:?}", *state.borrow_and_update());
+///         }
+///     });
+///
+///     while let Ok(request) = rpc.poll().await {
+///         println!("Incoming request: {}", request.endpoint());
+///     }
+/// }
+/// ```
+pub struct ReconnectingRpc {
+    /// Peer socket path
+    path: PathBuf,
+    /// Current RPC handle
+    rpc: Rpc,
+    /// Reconnection backoff
+    backoff: Backoff,
+    /// Reconnection attempts are exhausted
+    closed: bool,
+    /// Handshake options if every connection starts with a handshake
+    handshake: Option<HandshakeOptions>,
+}
+
+impl ReconnectingRpc {
+    /// Connect to the socket at `path`. Initial connection is retried using `backoff`.
+    /// Returns an error if connection attempts are exhausted
+    pub async fn connect(
+        path: impl AsRef<Path>,
+        peer_name: &str,
+        backoff: Backoff,
+    ) -> std::io::Result<Self> {
+        Self::connect_with(path.as_ref(), peer_name, None, backoff).await
+    }
+
+    /// Connect to the socket at `path` performing a handshake. See [Rpc::handshake].
+    /// The handshake is repeated after every reconnection. Failed handshakes are retried
+    /// using `backoff` as failed connection attempts
+    pub async fn handshake(
+        path: impl AsRef<Path>,
+        options: HandshakeOptions,
+        backoff: Backoff,
+    ) -> std::io::Result<Self> {
+        Self::connect_with(path.as_ref(), "", Some(options), backoff).await
+    }
+
+    async fn connect_with(
+        path: &Path,
+        peer_name: &str,
+        handshake: Option<HandshakeOptions>,
+        backoff: Backoff,
+    ) -> std::io::Result<Self> {
+        let rpc = match Self::connect_rpc(path, peer_name, handshake.as_ref()).await {
+            Ok(rpc) => rpc,
+            Err(e) => {
+                info!("Failed to connect to {}: {e}. Retrying", path.display());
+                Self::reconnect_with_backoff(path, peer_name, handshake.as_ref(), &backoff, None)
+                    .await?
+            }
+        };
+
+        let mut result = Self::new(rpc, path, backoff);
+        result.handshake = handshake;
+
+        Ok(result)
+    }
+
+    /// Make a reconnecting handle from an already connected `rpc`
+    pub fn new(rpc: Rpc, path: impl AsRef<Path>, backoff: Backoff) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            rpc,
+            backoff,
+            closed: false,
+            handshake: None,
+        }
+    }
+
+    /// Perform a handshake using `options` after every reconnection.
+    /// Use if `rpc` passed into [ReconnectingRpc::new] is made using [Rpc::handshake]
+    pub fn with_handshake(mut self, options: HandshakeOptions) -> Self {
+        self.handshake = Some(options);
+        self
+    }
+
+    /// Underlying RPC handle
+    pub fn rpc(&self) -> &Rpc {
+        &self.rpc
+    }
+
+    /// Poll RPC handle, resolving incoming responses and reconnecting if the connection is lost.
+    /// Returns next incoming request, or a reason why the connection has ended if
+    /// reconnection attempts are exhausted.
+    /// Dropping the future while reconnecting restarts reconnection on the next poll
+    pub async fn poll(&mut self) -> Result<RpcRequest, Disconnect> {
+        loop {
+            let reason = match self.rpc.poll().await {
+                Ok(request) => return Ok(request),
+                Err(reason) => reason,
+            };
+
+            if self.closed {
+                return Err(reason);
+            }
+
+            info!(
+                "Connection to {} lost: {reason}. Reconnecting",
+                self.rpc.peer_name()
+            );
+
+            let peer_name = self.rpc.peer_name().to_owned();
+            match Self::reconnect_with_backoff(
+                &self.path,
+                &peer_name,
+                self.handshake.as_ref(),
+                &self.backoff,
+                Some(self.rpc.writer()),
+            )
+            .await
+            {
+                Ok(rpc) => self.rpc.on_reconnected(rpc).await,
+                Err(e) => {
+                    warn!(
+                        "Failed to reconnect to {}: {e}. Giving up",
+                        self.rpc.peer_name()
+                    );
+
+                    self.closed = true;
+                    self.rpc.writer().set_disconnected();
+                    return Err(reason);
+                }
+            }
+        }
+    }
+
+    /// Connect to the socket at `path`, performing a handshake if `handshake` options are set
+    async fn connect_rpc(
+        path: &Path,
+        peer_name: &str,
+        handshake: Option<&HandshakeOptions>,
+    ) -> std::io::Result<Rpc> {
+        let stream = UnixStream::connect(path).await?;
+
+        match handshake {
+            Some(options) => Rpc::handshake(stream, options.clone())
+                .await
+                .map_err(std::io::Error::other),
+            None => Ok(Rpc::new(stream, peer_name)),
+        }
+    }
+
+    async fn reconnect_with_backoff(
+        path: &Path,
+        peer_name: &str,
+        handshake: Option<&HandshakeOptions>,
+        backoff: &Backoff,
+        writer: Option<&RpcWriter>,
+    ) -> std::io::Result<Rpc> {
+        let mut attempt = 1;
+
+        loop {
+            if let Some(writer) = writer {
+                writer.set_reconnecting(attempt);
+            }
+
+            let delay = backoff.delay(attempt);
+            debug!("Reconnection attempt {attempt} in {delay:?}");
+            tokio::time::sleep(delay).await;
+
+            match Self::connect_rpc(path, peer_name, handshake).await {
+                Ok(rpc) => return Ok(rpc),
+                Err(e) if backoff.max_attempts.is_some_and(|max| attempt >= max) => return Err(e),
+                Err(e) => debug!("Reconnection attempt {attempt} failed: {e}"),
+            }
+
+            attempt += 1;
+        }
+    }
+}
+
+impl Deref for ReconnectingRpc {
+    type Target = Rpc;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rpc
+    }
+}
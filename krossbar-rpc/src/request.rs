@@ -2,9 +2,11 @@ use std::fmt::{Debug, Formatter, Result as FmtResult};
 
 use bson::Bson;
 use serde::Serialize;
-use tokio::net::UnixStream;
 
-use super::writer::RpcWriter;
+use super::{
+    cancellation::Cancellation, fd::TypedFd, subscription::SubscriptionSink,
+    transport::PeerCredentials, writer::RpcWriter,
+};
 
 /// Incoming message body
 #[derive(Debug)]
@@ -15,11 +17,14 @@ pub enum Body {
     Call(Bson),
     /// Method subscription
     Subscription,
-    /// Incoming connection request in a form of UnixStream
+    /// Incoming connection request with a list of FDs.
+    /// Usually a single [crate::fd::FdKind::UnixStream]
     Fd {
         client_name: String,
         target_name: String,
-        stream: UnixStream,
+        /// Credentials of the process on the other side of the first stream in `fds`
+        credentials: Option<PeerCredentials>,
+        fds: Vec<TypedFd>,
     },
 }
 
@@ -33,15 +38,27 @@ pub struct RpcRequest {
     endpoint: String,
     /// Body. It's an option to allow user to steal body data
     body: Option<Body>,
+    /// Peer cancellation token
+    cancellation: Cancellation,
+    /// Peer process credentials at the moment the request was received
+    credentials: Option<PeerCredentials>,
 }
 
 impl RpcRequest {
-    pub(crate) fn new(message_id: i64, writer: RpcWriter, endpoint: String, body: Body) -> Self {
+    pub(crate) fn new(
+        message_id: i64,
+        writer: RpcWriter,
+        endpoint: String,
+        body: Body,
+        cancellation: Cancellation,
+    ) -> Self {
         Self {
             message_id,
+            credentials: writer.peer_credentials(),
             writer,
             endpoint,
             body: Some(body),
+            cancellation,
         }
     }
 
@@ -59,6 +76,12 @@ impl RpcRequest {
         self.writer.peer_name()
     }
 
+    /// Peer process credentials. Available only for [tokio::net::UnixStream] connections.
+    /// Can be used to authorize the request
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.credentials
+    }
+
     /// Request body. Moves the body out of the request. Can be used only once
     /// All subsequent calls will return `None`
     pub fn take_body(&mut self) -> Option<Body> {
@@ -75,19 +98,37 @@ impl RpcRequest {
         &self.endpoint
     }
 
+    /// Check if the peer has cancelled the call, unsubscribed, or disconnected.
+    /// One-way messages and connection requests are never cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Wait until the peer cancels the call, unsubscribes, or disconnects.
+    /// Can be used by long-running endpoints to stop early, or subscriptions to stop producing events
+    pub async fn cancelled(&self) {
+        self.cancellation.cancelled().await
+    }
+
+    /// Make a typed subscription sink from the request.
+    /// Should be used only for [Body::Subscription] requests
+    pub fn into_subscription<T: Serialize>(self) -> SubscriptionSink<T> {
+        SubscriptionSink::new(self)
+    }
+
     /// Respond to the call
     pub async fn respond<T: Serialize>(&self, data: Result<T, crate::Error>) -> bool {
         self.writer.respond(self.message_id, data).await
     }
 
-    /// Respond with FD
+    /// Respond with a list of FDs
     pub async fn respond_with_fd<T: Serialize>(
         &self,
         data: Result<T, crate::Error>,
-        stream: UnixStream,
+        fds: Vec<TypedFd>,
     ) -> bool {
         self.writer
-            .respond_with_fd(self.message_id, data, stream)
+            .respond_with_fd(self.message_id, data, fds)
             .await
     }
 }
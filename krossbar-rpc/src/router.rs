@@ -0,0 +1,280 @@
+use std::{collections::HashMap, sync::Arc};
+
+use bson::Bson;
+use futures::{future::BoxFuture, Future, FutureExt as _};
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{net::UnixStream, sync::Semaphore};
+
+use crate::{
+    fd::{FdKind, TypedFd},
+    request::{Body, RpcRequest},
+    rpc::Rpc,
+    subscription::SubscriptionSink,
+    Disconnect,
+};
+
+type MessageHandler = Box<dyn Fn(Bson) -> BoxFuture<'static, ()> + Send + Sync>;
+type CallHandler = Box<dyn Fn(Bson) -> BoxFuture<'static, crate::Result<Bson>> + Send + Sync>;
+type SubscriptionHandler = Box<dyn Fn(RpcRequest) -> BoxFuture<'static, ()> + Send + Sync>;
+type ConnectionHandler =
+    Box<dyn Fn(String, String, Vec<TypedFd>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Incoming requests router.
+/// Dispatches incoming requests to the handlers registered per endpoint and request kind.
+/// Deserializes request params and serializes handler results.
+/// Responds with [crate::Error::NoEndpoint] if there's no handler for a call or a subscription.
+/// Connection requests aren't responded, so unhandled ones are dropped
+///
+/// ```
+/// use tokio::net::UnixStream;
+///
+/// use krossbar_rpc::{router::Router, rpc::Rpc};
+///
+/// async fn serve() {
+///     let stream = UnixStream::connect("/tmp/hub.sock").await.unwrap();
+///     let mut rpc = Rpc::new(stream, "hub");
+///
+///     let router = Router::new()
+///         .on_call("echo", |value: u32| async move { Ok(value) })
+///         .on_message("log", |message: String| async move { println!("{message}") });
+///
+///     let reason = router.run(&mut rpc).await;
+///     println!("Client disconnected: {reason}");
+/// }
+/// ```
+#[derive(Default)]
+pub struct Router {
+    messages: HashMap<String, MessageHandler>,
+    calls: HashMap<String, CallHandler>,
+    subscriptions: HashMap<String, SubscriptionHandler>,
+    /// Connection request handlers by connection target name
+    connections: HashMap<String, ConnectionHandler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register one-way message handler.
+    /// Messages which body can't be deserialized into `P` are ignored
+    pub fn on_message<P, F, Fut>(mut self, endpoint: &str, handler: F) -> Self
+    where
+        P: DeserializeOwned,
+        F: Fn(P) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let endpoint_name = endpoint.to_owned();
+
+        self.messages.insert(
+            endpoint.to_owned(),
+            Box::new(move |body| match bson::from_bson(body) {
+                Ok(body) => handler(body).boxed(),
+                Err(e) => {
+                    warn!("Invalid `{endpoint_name}` message body: {e}");
+                    futures::future::ready(()).boxed()
+                }
+            }),
+        );
+        self
+    }
+
+    /// Register call handler.
+    /// Calls which params can't be deserialized into `P` are responded with [crate::Error::ParamsTypeError]
+    pub fn on_call<P, R, F, Fut>(mut self, endpoint: &str, handler: F) -> Self
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(P) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = crate::Result<R>> + Send + 'static,
+    {
+        self.calls.insert(
+            endpoint.to_owned(),
+            Box::new(move |params| match bson::from_bson(params) {
+                Ok(params) => handler(params)
+                    .map(|result| {
+                        result.and_then(|value| {
+                            bson::to_bson(&value)
+                                .map_err(|e| crate::Error::ResultTypeError(e.to_string()))
+                        })
+                    })
+                    .boxed(),
+                Err(e) => futures::future::ready(Err(crate::Error::ParamsTypeError(e.to_string())))
+                    .boxed(),
+            }),
+        );
+        self
+    }
+
+    /// Register subscription handler
+    pub fn on_subscription<T, F, Fut>(mut self, endpoint: &str, handler: F) -> Self
+    where
+        T: Serialize,
+        F: Fn(SubscriptionSink<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.subscriptions.insert(
+            endpoint.to_owned(),
+            Box::new(move |request| handler(request.into_subscription()).boxed()),
+        );
+        self
+    }
+
+    /// Register connection request handler for the `target_name`.
+    /// Handler receives initiator client name, target name, and the incoming stream.
+    /// Connection requests, which don't carry a single stream, are dropped.
+    /// Use [Router::on_connection_fds] to receive other FDs
+    pub fn on_connection<F, Fut>(self, target_name: &str, handler: F) -> Self
+    where
+        F: Fn(String, String, UnixStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_connection_fds(target_name, move |client_name, target_name, fds| {
+            match Self::connection_stream(fds) {
+                Some(stream) => handler(client_name, target_name, stream).boxed(),
+                None => {
+                    warn!(
+                        "Connection request from {client_name} to {target_name} without a stream"
+                    );
+                    futures::future::ready(()).boxed()
+                }
+            }
+        })
+    }
+
+    /// Register connection request handler for the `target_name`.
+    /// Handler receives initiator client name, target name, and the incoming FDs
+    pub fn on_connection_fds<F, Fut>(mut self, target_name: &str, handler: F) -> Self
+    where
+        F: Fn(String, String, Vec<TypedFd>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.connections.insert(
+            target_name.to_owned(),
+            Box::new(move |client_name, target_name, fds| {
+                handler(client_name, target_name, fds).boxed()
+            }),
+        );
+        self
+    }
+
+    /// Poll `rpc` and route incoming requests until the connection ends.
+    /// Messages, calls, and connection requests are handled one by one.
+    /// Subscription handlers are spawned, so long-lived subscriptions don't stop the connection
+    /// from being polled. Use [Router::serve] to handle all requests concurrently.
+    /// Must be called within Tokio runtime.
+    /// Returns the reason the connection has ended
+    pub async fn run(&self, rpc: &mut Rpc) -> Disconnect {
+        loop {
+            match rpc.poll().await {
+                Ok(request) => match self.subscription_handler(&request) {
+                    Some(handler) => {
+                        tokio::spawn(handler(request));
+                    }
+                    None => self.route(request).await,
+                },
+                Err(reason) => return reason,
+            }
+        }
+    }
+
+    /// Handler of a subscription `request` if registered
+    fn subscription_handler(&self, request: &RpcRequest) -> Option<&SubscriptionHandler> {
+        match request.body() {
+            Some(Body::Subscription) => self.subscriptions.get(request.endpoint()),
+            _ => None,
+        }
+    }
+
+    /// Poll `rpc` and spawn a handler task for every incoming request.
+    /// At most `max_concurrency` handlers run at the same time. Other spawned requests wait for
+    /// a free slot. `rpc` keeps being polled, so responses to the outgoing calls are resolved,
+    /// and handlers can make calls over the same connection.
+    /// Subscription handlers don't occupy a slot, because they may live as long as the connection.
+    /// Returns the reason the connection has ended. Handlers already spawned keep running.
+    /// Panics if `max_concurrency` is zero
+    pub async fn serve(self, rpc: &mut Rpc, max_concurrency: usize) -> Disconnect {
+        assert!(
+            max_concurrency > 0,
+            "Router concurrency limit must be positive"
+        );
+
+        let router = Arc::new(self);
+        let semaphore = Arc::new(Semaphore::new(max_concurrency));
+
+        loop {
+            match rpc.poll().await {
+                Ok(request) => {
+                    if let Some(handler) = router.subscription_handler(&request) {
+                        tokio::spawn(handler(request));
+                        continue;
+                    }
+
+                    let router = router.clone();
+                    let semaphore = semaphore.clone();
+
+                    tokio::spawn(async move {
+                        // Semaphore is never closed
+                        let _permit = semaphore.acquire_owned().await.unwrap();
+                        router.route(request).await
+                    });
+                }
+                Err(reason) => return reason,
+            }
+        }
+    }
+
+    /// Route a single request to a registered handler
+    pub async fn route(&self, mut request: RpcRequest) {
+        let Some(body) = request.take_body() else {
+            warn!("Request without a body: {request:?}");
+            return;
+        };
+
+        match body {
+            Body::Message(body) => match self.messages.get(request.endpoint()) {
+                Some(handler) => handler(body).await,
+                // Can't respond to one-way messages
+                None => debug!("No handler for `{}` message", request.endpoint()),
+            },
+            Body::Call(params) => match self.calls.get(request.endpoint()) {
+                Some(handler) => {
+                    let result = handler(params).await;
+                    request.respond(result).await;
+                }
+                None => Self::respond_no_endpoint(&request).await,
+            },
+            Body::Subscription => match self.subscriptions.get(request.endpoint()) {
+                Some(handler) => handler(request).await,
+                None => Self::respond_no_endpoint(&request).await,
+            },
+            Body::Fd {
+                client_name,
+                target_name,
+                fds,
+                ..
+            } => match self.connections.get(&target_name) {
+                Some(handler) => handler(client_name, target_name, fds).await,
+                // Connection requests aren't responded. Incoming FDs are dropped
+                None => {
+                    debug!("No handler for connection request from {client_name} to {target_name}")
+                }
+            },
+        }
+    }
+
+    /// Connection stream of a connection request. Expects a single [FdKind::UnixStream]
+    fn connection_stream(fds: Vec<TypedFd>) -> Option<UnixStream> {
+        match <[TypedFd; 1]>::try_from(fds) {
+            Ok([fd]) if fd.kind() == FdKind::UnixStream => fd.into_unix_stream().ok(),
+            _ => None,
+        }
+    }
+
+    async fn respond_no_endpoint(request: &RpcRequest) {
+        debug!("No handler for `{}` request", request.endpoint());
+
+        request.respond::<()>(Err(crate::Error::NoEndpoint)).await;
+    }
+}
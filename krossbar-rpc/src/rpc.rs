@@ -1,62 +1,315 @@
-use std::{ops::Deref, os::fd::AsRawFd, sync::Arc};
+use std::{ops::Deref, sync::Arc, time::Duration};
 
-use async_send_fd::AsyncRecvTokioStream as _;
-use futures::lock::Mutex;
 use log::{debug, info, trace, warn};
-use tokio::net::{unix::OwnedReadHalf, UnixStream};
 
 use crate::{
-    calls_registry::CallsRegistry,
+    access::{AccessPolicy, AccessRequest, RequestKind},
+    calls_registry::{CallsRegistry, SubscriptionDelivery},
+    cancellation::{Cancellation, CancellationRegistry},
+    codec::Codec,
+    fd::{FdKind, TypedFd},
+    handshake::{HandshakeOptions, Hello, PeerInfo},
     message::{self, RpcMessage},
     message_stream::AsyncReadMessage,
     request::{Body, RpcRequest},
+    shm,
+    subscription_queue::Push,
+    transport::{PeerCredentials, PlainHalf, Transport, TransportRead},
     writer::{self, RpcWriter},
+    Disconnect, HandshakeError,
 };
 
+/// Default max incoming message size: 16MB
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Max number of FDs following a message. Matches the kernel limit of FDs in a single
+/// `SCM_RIGHTS` message
+const MAX_MESSAGE_FDS: usize = 253;
+
+/// Closes the calls registry when the [Rpc] is dropped. Writer handles keep the registry
+/// alive, but nothing is going to resolve pending calls after the connection reader is gone
+struct RegistryGuard(Arc<CallsRegistry>);
+
+impl Deref for RegistryGuard {
+    type Target = CallsRegistry;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for RegistryGuard {
+    fn drop(&mut self) {
+        self.0.close()
+    }
+}
+
 /// RPC handle to a client
 pub struct Rpc {
     /// Verbose peer name
     peer_name: String,
     /// Socket reader to read incoming message
-    socket: OwnedReadHalf,
+    socket: Box<dyn TransportRead>,
     /// Socker writer handle to send responses
     writer: writer::RpcWriter,
-    /// Call registry to resolve incoming responses
-    calls_registry: Arc<Mutex<CallsRegistry>>,
+    /// Call registry to resolve incoming responses. Closed when the handle is dropped
+    calls_registry: RegistryGuard,
+    /// Incoming calls registry to notify about peer cancellations
+    cancellation_registry: CancellationRegistry,
+    /// Max incoming message size
+    max_message_size: usize,
+    /// Incoming message buffer, reused between messages
+    read_buffer: Vec<u8>,
+    /// Number of reconnections
+    generation: u64,
+    /// Messages codec
+    codec: Codec,
+    /// Peer parameters if connected using [Rpc::handshake]
+    peer_info: Option<PeerInfo>,
+    /// Incoming requests authorization policy
+    access_policy: Option<Box<dyn AccessPolicy>>,
+    /// Shared memory threshold set using [Rpc::with_shared_memory]
+    shared_memory_threshold: Option<usize>,
 }
 
 impl Rpc {
-    /// Make new RPC wrapper from [tokio::net::UnixStream].
+    /// Make new RPC wrapper from [tokio::net::UnixStream], or any other [Transport].
     /// `name` is a vebose peer name which is accessible from [RpcWriter] and [RpcRequest]
-    pub fn new(stream: UnixStream, peer_name: &str) -> Self {
+    pub fn new<T: Transport>(stream: T, peer_name: &str) -> Self {
         trace!("Making new RPC handle from a stream");
 
-        let calls_registry = Arc::new(Mutex::new(CallsRegistry::new()));
+        let calls_registry = Arc::new(CallsRegistry::new());
+        let credentials = stream.peer_credentials();
         let (reader, writer) = stream.into_split();
 
         Self {
             peer_name: peer_name.to_owned(),
-            socket: reader,
-            writer: RpcWriter::new(writer, calls_registry.clone(), peer_name),
-            calls_registry,
+            socket: Box::new(reader),
+            writer: RpcWriter::new(
+                Box::new(writer),
+                calls_registry.clone(),
+                peer_name,
+                credentials,
+            ),
+            calls_registry: RegistryGuard(calls_registry),
+            cancellation_registry: CancellationRegistry::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            read_buffer: Vec::new(),
+            generation: 0,
+            codec: Codec::default(),
+            peer_info: None,
+            access_policy: None,
+            shared_memory_threshold: None,
         }
     }
 
+    /// Make new RPC wrapper performing a handshake with the peer. Both peers exchange
+    /// protocol version, codec, supported features, and verbose names. Fails with a [HandshakeError]
+    /// if the peer is incompatible, or doesn't support handshake.
+    /// Peer name is taken from the peer hello. See [Rpc::peer_info] for negotiated parameters
+    ///
+    /// ```
+    /// use tokio::net::UnixStream;
+    ///
+    /// use krossbar_rpc::{codec::Codec, handshake::HandshakeOptions, rpc::Rpc};
+    ///
+    /// async fn connect() {
+    ///     let stream = UnixStream::connect("/tmp/hub.sock").await.unwrap();
+    ///
+    ///     let options = HandshakeOptions::new("client").codec(Codec::MessagePack);
+    ///     match Rpc::handshake(stream, options).await {
+    ///         Ok(rpc) => println!("Connected to {}", rpc.peer_name()),
+    ///         Err(e) => println!("Incompatible peer: {e}"),
+    ///     }
+    /// }
+    /// ```
+    pub async fn handshake<T: Transport>(
+        stream: T,
+        options: HandshakeOptions,
+    ) -> Result<Self, HandshakeError> {
+        let mut rpc = Self::new(stream, "").with_codec(options.codec);
+
+        let hello = Hello::new(&options, rpc.writer.supports_fd_passing().await);
+        rpc.writer
+            .write_hello(&hello)
+            .await
+            .map_err(Disconnect::Io)?;
+
+        let peer_hello: Hello = match tokio::time::timeout(
+            options.timeout,
+            rpc.socket
+                .read_message(&mut rpc.read_buffer, rpc.max_message_size, Codec::Bson),
+        )
+        .await
+        {
+            Ok(Ok(hello)) => hello,
+            // Peer sent a message, which is not a hello
+            Ok(Err(Disconnect::UnknownMessage(_) | Disconnect::CodecMismatch { .. })) => {
+                return Err(HandshakeError::NotSupported)
+            }
+            Ok(Err(reason)) => return Err(reason.into()),
+            Err(_) => return Err(HandshakeError::Timeout),
+        };
+
+        let peer_info = hello.negotiate(peer_hello, &options)?;
+        debug!("Handshake with {} succeeded: {peer_info:?}", peer_info.name);
+
+        rpc.peer_name.clone_from(&peer_info.name);
+        rpc.writer.set_peer_name(&peer_info.name);
+        rpc.peer_info = Some(peer_info);
+
+        Ok(rpc)
+    }
+
+    /// Set max incoming message size. Receiving a larger message is considered a protocol
+    /// violation, which closes the connection. Default is [DEFAULT_MAX_MESSAGE_SIZE]
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Set default timeout for outgoing calls. Calls, which are not responded within the `timeout`
+    /// fail with [crate::Error::Timeout]. Can be overridden per call using [writer::CallOptions]
+    pub fn with_call_timeout(mut self, timeout: Duration) -> Self {
+        self.writer.set_default_timeout(Some(timeout));
+        self
+    }
+
+    /// Set messages codec. Both peers must use the same codec. Default is [Codec::Bson]
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self.writer.set_codec(codec);
+        self
+    }
+
+    /// Check every incoming request using access `policy` before returning it from [Rpc::poll].
+    /// Disallowed calls and subscriptions are responded with [crate::Error::NotAllowed].
+    /// Disallowed one-way messages and connection requests are dropped.
+    /// See [crate::access]
+    pub fn with_access_policy(mut self, policy: impl AccessPolicy + 'static) -> Self {
+        self.access_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Send messages, which encode into more than `threshold` bytes, using a sealed memfd to avoid
+    /// copying large payloads through the socket. The receiver maps the memory and decodes
+    /// the message as usual, so calls, responses, and other messages switch to it transparently.
+    /// Messages with FDs are always sent inline.
+    /// The peer must support receiving shared memory messages, see [crate::handshake::Features].
+    /// Ignored if the transport doesn't support FD passing, or the peer negotiated in
+    /// [Rpc::handshake] doesn't support shared memory. Rechecked on reconnect.
+    /// Incoming shared memory messages are accepted only if enabled using this method, or
+    /// negotiated in [Rpc::handshake].
+    /// Outgoing messages are encoded directly into the memfd after they exceed `threshold`
+    pub fn with_shared_memory(mut self, threshold: usize) -> Self {
+        match self.writer.try_supports_fd_passing() {
+            Some(true) => {
+                self.shared_memory_threshold = Some(threshold);
+                self.update_shared_memory();
+            }
+            Some(false) => {
+                warn!("Failed to enable shared memory: transport doesn't support FD passing")
+            }
+            None => warn!("Failed to enable shared memory: the writer is in use"),
+        }
+
+        self
+    }
+
+    /// Enable shared memory messages if requested and supported by the peer
+    fn update_shared_memory(&self) {
+        let Some(threshold) = self.shared_memory_threshold else {
+            return;
+        };
+
+        if self
+            .peer_info
+            .as_ref()
+            .is_some_and(|info| !info.features.shared_memory)
+        {
+            warn!("Peer doesn't support shared memory messages");
+            self.writer.set_shared_memory(None);
+        } else {
+            self.writer.set_shared_memory(Some(threshold));
+        }
+    }
+
+    /// Batch outgoing messages to reduce the number of writes. Messages are coalesced into
+    /// a single vectored write when the writing task yields, after [writer::BatchOptions::flush_delay],
+    /// or as soon as the batch exceeds [writer::BatchOptions::max_size].
+    /// Messages with FDs are always written immediately after the pending batch.
+    /// Note that a batched message write error isn't returned to the sender.
+    /// The connection is marked disconnected instead, see [writer::RpcWriter::watch_connection_state]
+    pub fn with_write_batching(mut self, options: writer::BatchOptions) -> Self {
+        self.writer.set_batching(options);
+        self
+    }
+
+    /// Make writes from a dedicated task, which owns writing half of the socket.
+    /// Writer handles push outgoing messages into a queue of `queue_capacity` messages,
+    /// and wait only if the queue is full. Responses are written before other messages.
+    /// Queue depth is available via [writer::RpcWriter::write_queue_metrics].
+    /// Note that messages are considered sent once queued. If the writer task fails to write
+    /// a message, the connection is marked disconnected, and the following writes fail.
+    /// Calls and subscriptions, which haven't been written, fail with [crate::Error::PeerDisconnected].
+    /// The writer task is restarted on reconnect.
+    /// Must be called within Tokio runtime
+    pub fn with_writer_task(mut self, queue_capacity: usize) -> Self {
+        self.writer.start_writer_task(queue_capacity);
+        self
+    }
+
     /// Verbose peer name
     pub fn peer_name(&self) -> &str {
         &self.peer_name
     }
 
+    /// Peer process credentials. Available only for [tokio::net::UnixStream] connections.
+    /// Updated on reconnect
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.writer.peer_credentials()
+    }
+
+    /// Peer parameters negotiated in a handshake. Returns `None` if the connection
+    /// has been made without a handshake. See [Rpc::handshake]
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer_info.as_ref()
+    }
+
     /// Replace rpc stream with a new handle if reconnected.
-    /// Existing subscriptions will be resend to the client.
-    /// Pending calls will be discarded
+    /// Existing subscriptions and calls made with [writer::CallOptions::replay] will be resent to the client.
+    /// Other pending calls will fail with [crate::Error::PeerDisconnected]. Incoming requests will be cancelled.
+    /// Peer name and [Rpc::peer_info] are taken from `other`
     pub async fn on_reconnected(&mut self, other: Rpc) {
-        let Rpc { socket, writer, .. } = other;
+        let Rpc {
+            peer_name,
+            socket,
+            writer,
+            peer_info,
+            ..
+        } = other;
 
-        debug!("RPC reconnected. New socket: <{}>", socket.as_ref().as_raw_fd());
+        debug!("RPC to {peer_name} reconnected");
 
         self.socket = socket;
-        self.writer.on_reconnected(writer).await;
+        self.cancellation_registry.clear();
+        self.generation += 1;
+        self.writer.set_peer_name(&peer_name);
+        self.peer_name = peer_name;
+        self.peer_info = peer_info;
+        self.writer.on_reconnected(writer, self.generation).await;
+
+        // New peer may not support shared memory
+        self.update_shared_memory();
+    }
+
+    /// Close the connection. All subsequent polls return [Disconnect::Closed]
+    async fn close(&mut self) {
+        self.socket = Box::new(PlainHalf(tokio::io::empty()));
+        self.cancellation_registry.clear();
+        self.calls_registry.clear_pending_calls();
+        self.writer.shutdown().await;
+        self.writer.set_disconnected();
     }
 
     /// Get client writer
@@ -64,19 +317,163 @@ impl Rpc {
         &self.writer
     }
 
-    /// Poll RPC handle, resolving incoming responses
-    pub async fn poll(&mut self) -> Option<RpcRequest> {
+    /// Read next incoming message.
+    /// Large messages sent using shared memory are decoded from the received memfd
+    async fn next_message(&mut self) -> Result<RpcMessage, Disconnect> {
+        let message: RpcMessage = self
+            .socket
+            .read_message(&mut self.read_buffer, self.max_message_size, self.codec)
+            .await?;
+
+        let message::RpcData::SharedMemory { size } = message.data else {
+            Self::check_fds_count(&message)?;
+            return Ok(message);
+        };
+
+        if !self.accepts_shared_memory() {
+            return Err(Disconnect::UnknownMessage(
+                "Shared memory messages aren't enabled".to_owned(),
+            ));
+        }
+
+        let memfd = self.socket.recv_fd().await?;
+        let frame = shm::map_frame(memfd, size as usize, self.max_message_size)?;
+
+        let message: RpcMessage = self.codec.decode(&frame)?;
+        if let message::RpcData::SharedMemory { .. } = message.data {
+            return Err(Disconnect::MalformedFrame(
+                "Nested shared memory message".to_owned(),
+            ));
+        }
+
+        Self::check_fds_count(&message)?;
+
+        trace!("Received {size} bytes message using shared memory");
+        Ok(message)
+    }
+
+    /// If shared memory messages are enabled locally, or negotiated with the peer
+    fn accepts_shared_memory(&self) -> bool {
+        self.shared_memory_threshold.is_some()
+            || self
+                .peer_info
+                .as_ref()
+                .is_some_and(|info| info.features.shared_memory)
+    }
+
+    /// Check the number of FDs the peer is going to send after the `message`.
+    /// Returns [Disconnect::MalformedFrame] if exceeds [MAX_MESSAGE_FDS]
+    fn check_fds_count(message: &RpcMessage) -> Result<(), Disconnect> {
+        let count = match message.data {
+            message::RpcData::ConnectionRequest { ref fds, .. }
+            | message::RpcData::FdResponse { ref fds, .. } => fds.len(),
+            _ => return Ok(()),
+        };
+
+        if count > MAX_MESSAGE_FDS {
+            return Err(Disconnect::MalformedFrame(format!(
+                "Invalid FDs count {count}. Max FDs count: {MAX_MESSAGE_FDS}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Receive FDs of the `kinds`, which follow an incoming message.
+    /// The number of FDs is checked in [Rpc::next_message]
+    async fn recv_fds(
+        socket: &mut Box<dyn TransportRead>,
+        kinds: Vec<FdKind>,
+    ) -> std::io::Result<Vec<TypedFd>> {
+        let mut fds = Vec::with_capacity(kinds.len());
+
+        for kind in kinds {
+            fds.push(TypedFd::new(kind, socket.recv_fd().await?));
+        }
+
+        Ok(fds)
+    }
+
+    /// Check incoming request using access policy if set
+    fn is_allowed(&self, kind: RequestKind, endpoint: &str) -> bool {
+        let Some(ref policy) = self.access_policy else {
+            return true;
+        };
+
+        let request = AccessRequest {
+            kind,
+            endpoint,
+            peer_name: &self.peer_name,
+            credentials: self.writer.peer_credentials(),
+        };
+
+        let allowed = policy.is_allowed(&request);
+        if !allowed {
+            info!(
+                "Request from {} is not allowed: {request:?}",
+                self.peer_name
+            );
+        }
+
+        allowed
+    }
+
+    /// Push subscription response into the subscription queue.
+    /// Doesn't borrow `self`, because reading half of the socket isn't `Sync`
+    async fn deliver_subscription_response(
+        writer: &RpcWriter,
+        calls_registry: &CallsRegistry,
+        delivery: SubscriptionDelivery,
+    ) {
+        let id = delivery.id;
+
+        match delivery.push() {
+            Push::Queued => debug!("Succesfully resolved {id} subscription"),
+            Push::Parked => debug!("Subscription {id} buffer is full. Parked a response"),
+            Push::Dropped => debug!("Subscription {id} buffer is full. Dropped a response"),
+            Push::Overflow => {
+                warn!("Subscription {id} buffer overflowed. Unsubscribing");
+
+                writer.cancel(id, message::RpcData::Unsubscribe).await
+            }
+            Push::ReceiverDropped => {
+                warn!("User dropped subscriptions handle. Failed to send a response");
+
+                // Remove subscription as no one is waiting for it anymore
+                calls_registry.remove(id)
+            }
+        }
+    }
+
+    /// Poll RPC handle, resolving incoming responses.
+    /// Returns next incoming request, or a reason why the connection has ended.
+    /// When the connection ends, pending calls fail with [crate::Error::PeerDisconnected],
+    /// except replayed calls and subscriptions, which are resent by [Rpc::on_reconnected].
+    /// Dropping the handle fails them too, and ends subscription streams
+    pub async fn poll(&mut self) -> Result<RpcRequest, Disconnect> {
         loop {
-            trace!("Reading data from <{}>", self.socket.as_ref().as_raw_fd());
+            trace!("Reading data from {}", self.peer_name);
 
-            let message: RpcMessage = match self.socket.read_message().await {
+            let message = match self.next_message().await {
                 Ok(message) => message,
-                Err(e) => {
-                    info!(
-                        "Failed to read incoming message. Client error: {}",
-                        e.to_string()
+                Err(e) if e.is_protocol_violation() => {
+                    warn!(
+                        "Protocol violation by {}: {e}. Closing connection",
+                        self.peer_name
                     );
-                    return None;
+
+                    self.close().await;
+                    return Err(e);
+                }
+                Err(e) => {
+                    info!("Failed to read incoming message. Client error: {e}");
+
+                    // Notify pending requests, that peer has disconnected
+                    self.cancellation_registry.clear();
+                    // Calls, which are not going to be replayed on reconnect, won't be responded
+                    self.calls_registry.clear_pending_calls();
+                    self.writer.set_disconnected();
+                    return Err(e);
                 }
             };
 
@@ -90,73 +487,119 @@ impl Rpc {
 
             match message.data {
                 message::RpcData::Message { endpoint, body } => {
-                    return Some(RpcRequest::new(
+                    if !self.is_allowed(RequestKind::Message, &endpoint) {
+                        continue;
+                    }
+
+                    return Ok(RpcRequest::new(
                         -1,
                         self.writer.clone(),
                         endpoint,
                         Body::Message(body),
+                        Cancellation::never(),
                     ));
                 }
                 message::RpcData::Call { endpoint, params } => {
-                    return Some(RpcRequest::new(
+                    if !self.is_allowed(RequestKind::Call, &endpoint) {
+                        self.writer
+                            .respond::<()>(message.id, Err(crate::Error::NotAllowed))
+                            .await;
+                        continue;
+                    }
+
+                    return Ok(RpcRequest::new(
                         message.id,
                         self.writer.clone(),
                         endpoint,
                         Body::Call(params),
+                        self.cancellation_registry.add(message.id),
                     ));
                 }
                 message::RpcData::Subscription { endpoint } => {
-                    return Some(RpcRequest::new(
+                    if !self.is_allowed(RequestKind::Subscription, &endpoint) {
+                        self.writer
+                            .respond::<()>(message.id, Err(crate::Error::NotAllowed))
+                            .await;
+                        continue;
+                    }
+
+                    return Ok(RpcRequest::new(
                         message.id,
                         self.writer.clone(),
                         endpoint,
                         Body::Subscription,
+                        self.cancellation_registry.add(message.id),
                     ));
                 }
                 message::RpcData::ConnectionRequest {
                     client_name,
                     target_name,
-                } => match self.socket.recv_stream().await {
-                    Ok(stream) => {
-                        return Some(RpcRequest::new(
-                            message.id,
-                            self.writer.clone(),
-                            "connect".to_owned(),
-                            Body::Fd {
-                                client_name,
-                                target_name,
-                                stream,
-                            },
-                        ))
+                    fds,
+                } => {
+                    let Ok(fds) = Self::recv_fds(&mut self.socket, fds).await else {
+                        warn!("Failed to recieve incoming connection fds");
+                        continue;
+                    };
+
+                    let kind = RequestKind::Connection {
+                        client_name: &client_name,
+                        target_name: &target_name,
+                    };
+
+                    if !self.is_allowed(kind, "connect") {
+                        // Connection requests aren't responded. Incoming FDs are dropped
+                        debug!(
+                            "Connection request from {client_name} to {target_name} isn't allowed"
+                        );
+                        continue;
                     }
-                    Err(_) => warn!("Failed to recieve incoming connection fd"),
-                },
+
+                    return Ok(RpcRequest::new(
+                        message.id,
+                        self.writer.clone(),
+                        "connect".to_owned(),
+                        Body::Fd {
+                            client_name,
+                            target_name,
+                            credentials: fds.iter().find_map(TypedFd::peer_credentials),
+                            fds,
+                        },
+                        Cancellation::never(),
+                    ));
+                }
                 message::RpcData::Response(body) => {
-                    self.calls_registry
-                        .lock()
-                        .await
-                        .resolve(message.id, body)
+                    let delivery = self.calls_registry.resolve(message.id, body);
+
+                    if let Some(delivery) = delivery {
+                        Self::deliver_subscription_response(
+                            &self.writer,
+                            &self.calls_registry,
+                            delivery,
+                        )
                         .await
+                    }
                 }
-                message::RpcData::FdResponse(body) => match body {
-                    Ok(body) => match self.socket.recv_stream().await {
-                        Ok(stream) => self.calls_registry.lock().await.resolve_with_fd(
-                            message.id,
-                            Ok(body),
-                            Some(stream),
-                        ),
-                        Err(_) => self.calls_registry.lock().await.resolve_with_fd(
+                message::RpcData::FdResponse { result, fds } => {
+                    match Self::recv_fds(&mut self.socket, fds).await {
+                        Ok(fds) => self.calls_registry.resolve_with_fd(message.id, result, fds),
+                        Err(_) => self.calls_registry.resolve_with_fd(
                             message.id,
                             Err(crate::Error::PeerDisconnected),
-                            None,
+                            Vec::new(),
                         ),
-                    },
-                    e => self
-                        .calls_registry
-                        .lock()
-                        .await
-                        .resolve_with_fd(message.id, e, None),
-                },
+                    }
+                }
+                message::RpcData::Cancel | message::RpcData::Unsubscribe => {
+                    self.cancellation_registry.cancel(message.id)
+                }
+                message::RpcData::EndOfStream => {
+                    debug!("Peer closed subscription {}", message.id);
+
+                    // Dropping subscription sender terminates the stream
+                    self.calls_registry.remove(message.id)
+                }
+                // Resolved by `next_message`
+                message::RpcData::SharedMemory { .. } => {}
             }
         }
     }
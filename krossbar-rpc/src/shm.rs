@@ -0,0 +1,343 @@
+use std::{
+    fs::File,
+    io::{Error, Result, Write},
+    os::{fd::OwnedFd, unix::fs::FileExt},
+};
+
+use memmap2::Mmap;
+
+use crate::{
+    codec::FrameWrite,
+    fd::{FdKind, TypedFd},
+    Disconnect,
+};
+
+/// Size of the chunks written into a memfd after a frame has been moved into it
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Make an empty memfd, which can be sealed
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn create_memfd() -> Result<File> {
+    use std::{ffi::CStr, os::fd::FromRawFd};
+
+    let name = CStr::from_bytes_with_nul(b"krossbar-rpc\0").unwrap();
+
+    // Safety: `name` is a valid C string
+    let fd =
+        unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    // Safety: the descriptor is new and owned by us
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Make an empty memfd. Not supported on this platform
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn create_memfd() -> Result<File> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Shared memory isn't supported",
+    ))
+}
+
+/// Seal a memfd, so it can't be modified after sending
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn seal(file: File) -> Result<TypedFd> {
+    use std::os::fd::AsRawFd;
+
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+
+    // Safety: the descriptor is valid
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(TypedFd::new(FdKind::Memfd, file))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn seal(_file: File) -> Result<TypedFd> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Shared memory isn't supported",
+    ))
+}
+
+/// Encoded frame, see [FrameWriter::finish]
+pub enum SharedFrame {
+    /// Frame hasn't exceeded the threshold, or shared memory is unavailable
+    Inline(Vec<u8>),
+    /// Frame has been placed into a sealed memfd. `buffer` can be reused
+    Shared {
+        memfd: TypedFd,
+        size: usize,
+        buffer: Vec<u8>,
+    },
+}
+
+/// Frame writer, which keeps a frame in a `buffer` until it exceeds the `threshold`,
+/// and then moves it into a memfd. The rest of the frame is written directly into the memfd,
+/// so a large frame isn't kept in memory and copied afterwards.
+/// Falls back to keeping the whole frame in the buffer if fails to make a memfd
+pub struct FrameWriter {
+    buffer: Vec<u8>,
+    threshold: usize,
+    memfd: Option<File>,
+    /// Number of bytes already moved into the memfd
+    shared: usize,
+    /// Error, which prevented making the memfd
+    error: Option<Error>,
+}
+
+impl FrameWriter {
+    /// Make a writer using an empty `buffer`
+    pub fn new(buffer: Vec<u8>, threshold: usize) -> Self {
+        Self {
+            buffer,
+            threshold,
+            memfd: None,
+            shared: 0,
+            error: None,
+        }
+    }
+
+    /// Error, which made the frame stay inline
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    /// Write buffered data into the memfd, making one if the frame has exceeded the threshold
+    fn spill(&mut self) -> Result<()> {
+        let memfd = match self.memfd {
+            Some(ref mut memfd) if self.buffer.len() >= WRITE_CHUNK_SIZE => memfd,
+            None if self.error.is_none() && self.buffer.len() > self.threshold => {
+                match create_memfd() {
+                    Ok(memfd) => self.memfd.insert(memfd),
+                    Err(e) => {
+                        self.error = Some(e);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => return Ok(()),
+        };
+
+        memfd.write_all(&self.buffer)?;
+        self.shared += self.buffer.len();
+        self.buffer.clear();
+
+        Ok(())
+    }
+
+    /// Write the rest of the frame, and seal the memfd if the frame has been moved into it
+    pub fn finish(mut self) -> Result<SharedFrame> {
+        let Some(mut memfd) = self.memfd.take() else {
+            return Ok(SharedFrame::Inline(self.buffer));
+        };
+
+        memfd.write_all(&self.buffer)?;
+        let size = self.position();
+        self.buffer.clear();
+
+        Ok(SharedFrame::Shared {
+            memfd: seal(memfd)?,
+            size,
+            buffer: self.buffer,
+        })
+    }
+
+    /// Take back the buffer if encoding has failed
+    pub fn into_buffer(mut self) -> Vec<u8> {
+        self.buffer.clear();
+        self.buffer
+    }
+}
+
+impl Write for FrameWriter {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.buffer.extend_from_slice(data);
+        self.spill()?;
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl FrameWrite for FrameWriter {
+    fn position(&self) -> usize {
+        self.shared + self.buffer.len()
+    }
+
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        // Part of the data, which overwrites bytes already moved into the memfd
+        let (shared, buffered) = data.split_at(self.shared.saturating_sub(offset).min(data.len()));
+
+        if let Some(ref memfd) = self.memfd {
+            memfd.write_all_at(shared, offset as u64)?;
+        }
+
+        if !buffered.is_empty() {
+            let start = offset + shared.len() - self.shared;
+            self.buffer[start..start + buffered.len()].copy_from_slice(buffered);
+        }
+
+        Ok(())
+    }
+
+    /// Move a separately serialized frame into the memfd without copying it into the buffer
+    fn put_frame(&mut self, frame: Vec<u8>) -> Result<()> {
+        let buffer = std::mem::replace(&mut self.buffer, frame);
+        self.spill()?;
+
+        // Keep the original buffer if the frame has been moved into the memfd
+        if self.buffer.is_empty() {
+            self.buffer = buffer;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check if the peer can't modify memfd content after sending
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn is_sealed(file: &File) -> bool {
+    use std::os::fd::AsRawFd;
+
+    let required = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+
+    // Safety: the descriptor is valid
+    let seals = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+    seals >= 0 && seals & required == required
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn is_sealed(_file: &File) -> bool {
+    false
+}
+
+/// Map a sealed memfd with a frame of `size` bytes received from the peer.
+/// Returns [Disconnect::MalformedFrame] if the memfd isn't sealed, or has unexpected size
+pub fn map_frame(
+    fd: OwnedFd,
+    size: usize,
+    max_size: usize,
+) -> std::result::Result<Mmap, Disconnect> {
+    if size > max_size {
+        return Err(Disconnect::MalformedFrame(format!(
+            "Invalid shared message length {size}. Max message size: {max_size}"
+        )));
+    }
+
+    let file = File::from(fd);
+
+    if !is_sealed(&file) {
+        return Err(Disconnect::MalformedFrame(
+            "Shared message memory isn't sealed".to_owned(),
+        ));
+    }
+
+    let file_size = file.metadata()?.len();
+    if file_size != size as u64 {
+        return Err(Disconnect::MalformedFrame(format!(
+            "Shared message length {file_size} doesn't match declared length {size}"
+        )));
+    }
+
+    // Safety: the memory can't be modified or truncated by the peer, because the memfd is sealed
+    unsafe { Mmap::map(&file) }.map_err(Disconnect::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Write};
+
+    use super::{map_frame, FrameWriter, SharedFrame, WRITE_CHUNK_SIZE};
+    use crate::{codec::Codec, fd::TypedFd, Disconnect};
+
+    fn seal_frame(frame: &[u8]) -> TypedFd {
+        let mut writer = FrameWriter::new(Vec::new(), 0);
+        writer.write_all(frame).unwrap();
+
+        match writer.finish().unwrap() {
+            SharedFrame::Shared { memfd, .. } => memfd,
+            SharedFrame::Inline(_) => panic!("Frame hasn't been shared"),
+        }
+    }
+
+    #[test]
+    fn test_sealed_frame() {
+        let frame = vec![42u8; 4096];
+        let fd = seal_frame(&frame);
+
+        let map = map_frame(fd.into_fd(), frame.len(), frame.len()).unwrap();
+        assert_eq!(&map[..], &frame[..]);
+    }
+
+    #[test]
+    fn test_frame_writer() {
+        const THRESHOLD: usize = 1024;
+
+        for codec in [Codec::Bson, Codec::MessagePack, Codec::Cbor] {
+            for size in [16, THRESHOLD, 3 * WRITE_CHUNK_SIZE] {
+                let message = bson::doc! { "data": "x".repeat(size) };
+                let expected = codec.encode(&message).unwrap();
+
+                let mut writer = FrameWriter::new(Vec::new(), THRESHOLD);
+                codec.encode_to(&message, &mut writer).unwrap();
+
+                match writer.finish().unwrap() {
+                    SharedFrame::Inline(frame) => {
+                        assert!(expected.len() <= THRESHOLD);
+                        assert_eq!(frame, expected);
+                    }
+                    SharedFrame::Shared {
+                        memfd,
+                        size,
+                        buffer,
+                    } => {
+                        assert!(expected.len() > THRESHOLD);
+                        assert!(buffer.is_empty());
+
+                        let map = map_frame(memfd.into_fd(), size, size).unwrap();
+                        assert_eq!(&map[..], &expected[..]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_frame() {
+        let frame = vec![42u8; 4096];
+
+        // Size mismatch
+        let fd = seal_frame(&frame);
+        assert!(matches!(
+            map_frame(fd.into_fd(), 1024, frame.len()),
+            Err(Disconnect::MalformedFrame(_))
+        ));
+
+        // Too large
+        let fd = seal_frame(&frame);
+        assert!(matches!(
+            map_frame(fd.into_fd(), frame.len(), 1024),
+            Err(Disconnect::MalformedFrame(_))
+        ));
+
+        // Not sealed
+        let path = std::env::temp_dir().join(format!("krossbar-shm-test-{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.write_all(&frame).unwrap();
+
+        assert!(matches!(
+            map_frame(file.into(), frame.len(), frame.len()),
+            Err(Disconnect::MalformedFrame(_))
+        ));
+    }
+}
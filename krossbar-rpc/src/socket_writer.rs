@@ -0,0 +1,264 @@
+use std::{
+    io::{Error, ErrorKind, IoSlice, Result},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::trace;
+use tokio::io::AsyncWriteExt;
+
+use crate::{fd::TypedFd, transport::TransportWrite, writer::BatchOptions};
+
+/// Max number of buffers passed into a single vectored write
+const MAX_IO_SLICES: usize = 1024;
+/// Max number of frame buffers kept for reuse
+const MAX_POOLED_BUFFERS: usize = 64;
+/// Max frame buffer capacity kept for reuse. Larger buffers are dropped after use
+const MAX_RETAINED_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Frame buffers, which are reused to encode outgoing messages.
+/// Shared by the writer handles, which encode messages, and the socket writer, which returns
+/// the buffers after the frames are written
+#[derive(Clone, Default)]
+pub(crate) struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    /// Take an empty buffer. Allocates a new one if the pool is empty
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Return a buffer into the pool
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > MAX_RETAINED_BUFFER_SIZE {
+            return;
+        }
+
+        buffer.clear();
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// Pending outgoing frames
+struct WriteBatch {
+    options: BatchOptions,
+    frames: Vec<Vec<u8>>,
+    /// Total size of the pending frames
+    size: usize,
+    /// If a delayed flush is scheduled
+    flush_scheduled: bool,
+}
+
+/// Writing half of the connection, which optionally batches outgoing frames
+pub(crate) struct SocketWriter {
+    stream: Box<dyn TransportWrite>,
+    batch: Option<WriteBatch>,
+    /// Written frames are returned into the pool
+    buffers: BufferPool,
+}
+
+impl SocketWriter {
+    pub fn new(stream: Box<dyn TransportWrite>) -> Self {
+        Self {
+            stream,
+            batch: None,
+            buffers: BufferPool::default(),
+        }
+    }
+
+    /// Frame buffers pool. Frames taken from the pool are returned into it after writing
+    pub fn buffers(&self) -> &BufferPool {
+        &self.buffers
+    }
+
+    /// Enable write batching. Expected to be called before any data is written
+    pub fn set_batching(&mut self, options: BatchOptions) {
+        self.batch = Some(WriteBatch {
+            options,
+            frames: Vec::new(),
+            size: 0,
+            flush_scheduled: false,
+        });
+    }
+
+    /// Replace connection stream. Pending frames are dropped, because they belong to the old connection
+    pub fn replace_stream(&mut self, other: SocketWriter) {
+        self.stream = other.stream;
+
+        if let Some(batch) = self.batch.as_mut() {
+            batch.frames.clear();
+            batch.size = 0;
+        }
+    }
+
+    /// Write an encoded message. If batching is enabled, the frame is queued and written
+    /// when the batch exceeds max size, or on delayed flush.
+    /// Returns a flush delay if the caller should schedule a delayed flush using [Self::flush_batch]
+    pub async fn write_frame(&mut self, frame: Vec<u8>) -> Result<Option<Duration>> {
+        let Some(batch) = self.batch.as_mut() else {
+            let result = self.stream.write_all(&frame).await;
+            self.buffers.put(frame);
+
+            return result.map(|_| None);
+        };
+
+        batch.size += frame.len();
+        batch.frames.push(frame);
+
+        if batch.size >= batch.options.max_size {
+            self.flush_batch().await?;
+            Ok(None)
+        } else if !batch.flush_scheduled {
+            batch.flush_scheduled = true;
+            Ok(Some(batch.options.flush_delay))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Write pending frames using vectored writes
+    pub async fn flush_batch(&mut self) -> Result<()> {
+        let Some(batch) = self.batch.as_mut() else {
+            return Ok(());
+        };
+
+        batch.flush_scheduled = false;
+        if batch.frames.is_empty() {
+            return Ok(());
+        }
+
+        trace!(
+            "Flushing {} frames of {} bytes",
+            batch.frames.len(),
+            batch.size
+        );
+
+        let result = write_all_vectored(&mut self.stream, &batch.frames).await;
+
+        for frame in batch.frames.drain(..) {
+            self.buffers.put(frame);
+        }
+        batch.size = 0;
+        result
+    }
+
+    /// Send `fds` after all the pending frames to keep the descriptors ordered with their message
+    pub async fn send_fds(&mut self, fds: Vec<TypedFd>) -> Result<()> {
+        self.flush_batch().await?;
+
+        for fd in fds {
+            self.stream.send_fd(fd.into_fd()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush pending frames and the stream
+    pub async fn flush(&mut self) -> Result<()> {
+        self.flush_batch().await?;
+        self.stream.flush().await
+    }
+
+    /// Flush pending frames and shutdown the stream
+    pub async fn shutdown(&mut self) -> Result<()> {
+        let _ = self.flush_batch().await;
+        self.stream.shutdown().await
+    }
+
+    pub fn supports_fd_passing(&self) -> bool {
+        self.stream.supports_fd_passing()
+    }
+}
+
+/// Write all `frames` using as few syscalls as possible
+async fn write_all_vectored(
+    stream: &mut Box<dyn TransportWrite>,
+    frames: &[Vec<u8>],
+) -> Result<()> {
+    let mut frame = 0;
+    // Offset inside the current frame
+    let mut offset = 0;
+
+    while frame < frames.len() {
+        let slices: Vec<IoSlice> = std::iter::once(&frames[frame][offset..])
+            .chain(frames[frame + 1..].iter().map(Vec::as_slice))
+            .take(MAX_IO_SLICES)
+            .map(IoSlice::new)
+            .collect();
+
+        let mut written = stream.write_vectored(&slices).await?;
+        if written == 0 {
+            return Err(Error::from(ErrorKind::WriteZero));
+        }
+
+        while frame < frames.len() && written > 0 {
+            let left = frames[frame].len() - offset;
+
+            if written >= left {
+                written -= left;
+                frame += 1;
+                offset = 0;
+            } else {
+                offset += written;
+                written = 0;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, SocketWriter, MAX_RETAINED_BUFFER_SIZE};
+    use crate::{codec::Codec, transport::Transport};
+
+    #[tokio::test]
+    async fn test_buffers_reused() {
+        let (stream, _peer) = tokio::io::duplex(1024 * 1024);
+        let (_, write) = stream.into_split();
+        let mut socket = SocketWriter::new(Box::new(write));
+
+        let mut frame = socket.buffers().take();
+        Codec::MessagePack
+            .encode_into(&"x".repeat(1024), &mut frame)
+            .unwrap();
+        let capacity = frame.capacity();
+
+        socket.write_frame(frame).await.unwrap();
+
+        // Next message is encoded into the same buffer
+        let frame = socket.buffers().take();
+        assert!(frame.is_empty());
+        assert_eq!(frame.capacity(), capacity);
+
+        // Large buffers aren't kept
+        let mut frame = frame;
+        Codec::MessagePack
+            .encode_into(&"x".repeat(MAX_RETAINED_BUFFER_SIZE), &mut frame)
+            .unwrap();
+        socket.write_frame(frame).await.unwrap();
+
+        assert_eq!(socket.buffers().take().capacity(), 0);
+    }
+
+    #[test]
+    fn test_pool_size() {
+        let pool = BufferPool::default();
+
+        for _ in 0..super::MAX_POOLED_BUFFERS * 2 {
+            pool.put(Vec::with_capacity(16));
+        }
+
+        assert_eq!(
+            pool.buffers.lock().unwrap().len(),
+            super::MAX_POOLED_BUFFERS
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{stream::FusedStream, Stream, StreamExt as _};
+use log::debug;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    message::RpcData, request::RpcRequest, subscription_queue::QueueReceiver, writer::RpcWriter,
+};
+
+/// Default number of subscription items buffered until the subscriber reads them
+pub const DEFAULT_BUFFER_SIZE: usize = 100;
+
+/// What to do with a new subscription item if the subscription buffer is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Keep all items. Items which don't fit into the buffer are parked until the subscriber
+    /// reads the buffered ones. The connection keeps being read, so a slow subscriber doesn't
+    /// block other calls and subscriptions, but memory used by the parked items isn't bounded.
+    /// Use only if the peer is trusted to send a limited number of items
+    Unbounded,
+    /// Drop the oldest buffered item, so the subscriber always gets the latest items
+    #[default]
+    DropOldest,
+    /// Drop the new item
+    DropNewest,
+    /// Replace the latest buffered item with the new one
+    CoalesceLatest,
+    /// Unsubscribe from the peer. The subscription stream ends with
+    /// [crate::Error::SubscriptionOverflow] after the buffered items
+    Disconnect,
+}
+
+/// Subscription options
+#[derive(Clone, Debug)]
+pub struct SubscriptionOptions {
+    pub(crate) buffer_size: usize,
+    pub(crate) overflow_policy: OverflowPolicy,
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        Self {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            overflow_policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl SubscriptionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Max number of buffered subscription items. Default is [DEFAULT_BUFFER_SIZE].
+    /// Panics if `buffer_size` is zero
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        assert!(buffer_size > 0, "Subscription buffer size must be positive");
+
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Buffer overflow policy. Default is [OverflowPolicy::DropOldest]
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+}
+
+/// Subscription stream returned by [RpcWriter::subscribe].
+/// The stream ends when the peer closes the subscription using [SubscriptionSink],
+/// or the [crate::rpc::Rpc] handle is dropped.
+/// Dropping the stream or calling [Subscription::close] unsubscribes from the peer
+pub struct Subscription<T> {
+    /// Subscription message id
+    id: i64,
+    /// Incoming subscription responses
+    receiver: QueueReceiver,
+    /// Writer to send unsubscribe message. `None` if already unsubscribed
+    writer: Option<RpcWriter>,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Subscription<T> {
+    pub(crate) fn new(id: i64, receiver: QueueReceiver, writer: RpcWriter) -> Self {
+        Self {
+            id,
+            receiver,
+            writer: Some(writer),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Subscription message id
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// Unsubscribe from the peer
+    pub async fn close(mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.cancel(self.id, RpcData::Unsubscribe).await
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Stream for Subscription<T> {
+    type Item = crate::Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx).map(|maybe_response| {
+            maybe_response.map(|response| {
+                response.and_then(|value| {
+                    bson::from_bson(value).map_err(|e| crate::Error::ResultTypeError(e.to_string()))
+                })
+            })
+        })
+    }
+}
+
+impl<T: DeserializeOwned> FusedStream for Subscription<T> {
+    fn is_terminated(&self) -> bool {
+        self.receiver.is_terminated()
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        // Nothing to unsubscribe from if the peer has closed the stream
+        if self.receiver.is_terminated() {
+            return;
+        }
+
+        if let Some(writer) = self.writer.take() {
+            debug!("Subscription {} dropped", self.id);
+
+            writer.cancel_in_background(self.id, RpcData::Unsubscribe)
+        }
+    }
+}
+
+/// Serving side of a subscription. Made from an incoming subscription request
+/// using [RpcRequest::into_subscription]
+pub struct SubscriptionSink<T> {
+    request: RpcRequest,
+    _phantom: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize> SubscriptionSink<T> {
+    pub(crate) fn new(request: RpcRequest) -> Self {
+        Self {
+            request,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Subscription endpoint
+    pub fn endpoint(&self) -> &String {
+        self.request.endpoint()
+    }
+
+    /// Verbose peer name
+    pub fn peer_name(&self) -> &str {
+        self.request.peer_name()
+    }
+
+    /// Check if the peer has unsubscribed, or disconnected
+    pub fn is_cancelled(&self) -> bool {
+        self.request.is_cancelled()
+    }
+
+    /// Wait until the peer unsubscribes, or disconnects
+    pub async fn cancelled(&self) {
+        self.request.cancelled().await
+    }
+
+    /// Send subscription item.
+    /// Returns `true` if succesfully sent
+    pub async fn send(&self, item: T) -> bool {
+        self.request.respond(Ok(item)).await
+    }
+
+    /// Send terminal error and close the stream.
+    /// Returns `true` if succesfully sent
+    pub async fn error(self, error: crate::Error) -> bool {
+        self.request.respond::<T>(Err(error)).await && self.close().await
+    }
+
+    /// Close the stream. Peer subscription stream ends after receiving all sent items.
+    /// Returns `true` if succesfully sent
+    pub async fn close(self) -> bool {
+        self.request
+            .writer()
+            .end_subscription(self.request.message_id())
+            .await
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use bson::Bson;
+use futures::{stream::FusedStream, Stream};
+
+use crate::subscription::OverflowPolicy;
+
+type Item = crate::Result<Bson>;
+
+/// Result of pushing an item into a subscription queue
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Push {
+    /// Item has been queued
+    Queued,
+    /// Buffer is full. Item has been parked until the subscriber reads the buffered items.
+    /// Used with [OverflowPolicy::Unbounded]
+    Parked,
+    /// Item or an older item has been dropped due to the overflow policy
+    Dropped,
+    /// Queue has overflowed and has been closed due to [OverflowPolicy::Disconnect]
+    Overflow,
+    /// Subscription stream has been dropped
+    ReceiverDropped,
+}
+
+struct State {
+    items: VecDeque<Item>,
+    /// Sender has been dropped or the queue has overflowed
+    closed: bool,
+    receiver_dropped: bool,
+    receiver_waker: Option<Waker>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+/// Make a subscription queue with a given positive `capacity` and overflow `policy`
+pub(crate) fn queue(capacity: usize, policy: OverflowPolicy) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            closed: false,
+            receiver_dropped: false,
+            receiver_waker: None,
+        }),
+        capacity,
+        policy,
+    });
+
+    (
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver {
+            shared,
+            terminated: false,
+        },
+    )
+}
+
+/// Sending half of the subscription queue. Closes the queue when dropped
+pub(crate) struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl QueueSender {
+    /// Push an item into the queue applying the queue overflow policy.
+    /// Never waits for the subscriber, so the connection reader isn't blocked by a slow subscriber
+    pub fn push(&self, item: Item) -> Push {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.receiver_dropped {
+            return Push::ReceiverDropped;
+        }
+
+        if state.closed {
+            return Push::Dropped;
+        }
+
+        let result = if state.items.len() < self.shared.capacity {
+            state.items.push_back(item);
+            Push::Queued
+        } else {
+            match self.shared.policy {
+                OverflowPolicy::Unbounded => {
+                    state.items.push_back(item);
+                    Push::Parked
+                }
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    state.items.push_back(item);
+                    Push::Dropped
+                }
+                OverflowPolicy::DropNewest => Push::Dropped,
+                OverflowPolicy::CoalesceLatest => {
+                    if let Some(last) = state.items.back_mut() {
+                        *last = item;
+                    }
+                    Push::Dropped
+                }
+                OverflowPolicy::Disconnect => {
+                    // Let the subscriber know why the stream has ended
+                    state
+                        .items
+                        .push_back(Err(crate::Error::SubscriptionOverflow));
+                    state.closed = true;
+                    Push::Overflow
+                }
+            }
+        };
+
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake()
+        }
+
+        result
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake()
+        }
+    }
+}
+
+/// Receiving half of the subscription queue
+pub(crate) struct QueueReceiver {
+    shared: Arc<Shared>,
+    terminated: bool,
+}
+
+impl Stream for QueueReceiver {
+    type Item = Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let Some(item) = state.items.pop_front() {
+            Poll::Ready(Some(item))
+        } else if state.closed {
+            drop(state);
+            self.terminated = true;
+
+            Poll::Ready(None)
+        } else {
+            state.receiver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl FusedStream for QueueReceiver {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_dropped = true;
+        state.items.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use futures::StreamExt;
+
+    use super::{queue, Push};
+    use crate::subscription::OverflowPolicy;
+
+    async fn collect(policy: OverflowPolicy) -> (Vec<Push>, Vec<crate::Result<Bson>>) {
+        let (sender, receiver) = queue(2, policy);
+
+        let mut pushes = Vec::new();
+        for i in 0..4 {
+            pushes.push(sender.push(Ok(Bson::Int32(i))));
+        }
+
+        drop(sender);
+        (pushes, receiver.collect().await)
+    }
+
+    fn values(items: Vec<crate::Result<Bson>>) -> Vec<Option<i32>> {
+        items
+            .into_iter()
+            .map(|item| item.ok().and_then(|value| value.as_i32()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_overflow_policies() {
+        let (pushes, items) = collect(OverflowPolicy::DropOldest).await;
+        assert_eq!(
+            pushes,
+            vec![Push::Queued, Push::Queued, Push::Dropped, Push::Dropped]
+        );
+        assert_eq!(values(items), vec![Some(2), Some(3)]);
+
+        let (_, items) = collect(OverflowPolicy::DropNewest).await;
+        assert_eq!(values(items), vec![Some(0), Some(1)]);
+
+        let (_, items) = collect(OverflowPolicy::CoalesceLatest).await;
+        assert_eq!(values(items), vec![Some(0), Some(3)]);
+
+        let (pushes, items) = collect(OverflowPolicy::Disconnect).await;
+        assert_eq!(
+            pushes,
+            vec![Push::Queued, Push::Queued, Push::Overflow, Push::Dropped]
+        );
+        assert!(matches!(items[2], Err(crate::Error::SubscriptionOverflow)));
+        assert_eq!(values(items), vec![Some(0), Some(1), None]);
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_policy() {
+        let (pushes, items) = collect(OverflowPolicy::Unbounded).await;
+        assert_eq!(
+            pushes,
+            vec![Push::Queued, Push::Queued, Push::Parked, Push::Parked]
+        );
+        assert_eq!(values(items), vec![Some(0), Some(1), Some(2), Some(3)]);
+
+        // Parked items are released when the receiver is dropped
+        let (sender, receiver) = queue(1, OverflowPolicy::Unbounded);
+        assert_eq!(sender.push(Ok(Bson::Int32(0))), Push::Queued);
+        assert_eq!(sender.push(Ok(Bson::Int32(1))), Push::Parked);
+
+        drop(receiver);
+        assert_eq!(sender.push(Ok(Bson::Int32(2))), Push::ReceiverDropped);
+    }
+}
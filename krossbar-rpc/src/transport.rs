@@ -0,0 +1,253 @@
+//! Transports, which can be used to make [crate::rpc::Rpc] connection.
+//!
+//! Any [tokio::io::AsyncRead] + [tokio::io::AsyncWrite] pair can be used as a transport.
+//! FD passing is an optional capability, which is supported only by [tokio::net::UnixStream].
+use std::{
+    io::{Error, ErrorKind, IoSlice, Result},
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use passfd::FdPassingExt;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, Interest, ReadBuf, ReadHalf, WriteHalf},
+    net::{tcp, unix, TcpStream, UnixStream},
+};
+
+fn fd_passing_unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "Transport doesn't support FD passing",
+    )
+}
+
+/// Reading half of a transport
+pub trait TransportRead: AsyncRead + Send + Unpin {
+    /// Receive a file descriptor sent by the peer.
+    /// Transports without FD passing support return [ErrorKind::Unsupported]
+    fn recv_fd(&mut self) -> BoxFuture<'_, Result<OwnedFd>> {
+        Box::pin(async { Err(fd_passing_unsupported()) })
+    }
+}
+
+/// Writing half of a transport
+pub trait TransportWrite: AsyncWrite + Send + Unpin {
+    /// If transport is able to send and receive file descriptors
+    fn supports_fd_passing(&self) -> bool {
+        false
+    }
+
+    /// Send a file descriptor to the peer.
+    /// Transports without FD passing support return [ErrorKind::Unsupported]
+    fn send_fd(&mut self, _fd: OwnedFd) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Err(fd_passing_unsupported()) })
+    }
+}
+
+/// Credentials of the process on the other side of a Unix socket.
+/// Captured by the OS when the connection is made
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Peer process id. Not available on some platforms
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    /// Get peer credentials of a `stream`. Returns `None` if the OS fails to provide them
+    pub fn from_stream(stream: &UnixStream) -> Option<Self> {
+        stream.peer_cred().ok().map(|cred| Self {
+            pid: cred.pid(),
+            uid: cred.uid(),
+            gid: cred.gid(),
+        })
+    }
+
+    /// Get peer credentials of a Unix socket `fd`. Returns `None` if the OS fails to provide them
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn from_fd(fd: BorrowedFd) -> Option<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+        // Safety: `cred` and `len` are valid for writes and `len` matches `cred` size
+        let result = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+
+        (result == 0).then_some(Self {
+            pid: Some(cred.pid),
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+
+    /// Get peer credentials of a Unix socket `fd`. Not supported on this platform
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn from_fd(_fd: BorrowedFd) -> Option<Self> {
+        None
+    }
+}
+
+/// A connection, which can be split into reading and writing halves
+pub trait Transport {
+    type Read: TransportRead + 'static;
+    type Write: TransportWrite + 'static;
+
+    /// Peer process credentials. Supported only by [tokio::net::UnixStream]
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        None
+    }
+
+    fn into_split(self) -> (Self::Read, Self::Write);
+}
+
+impl TransportRead for unix::OwnedReadHalf {
+    fn recv_fd(&mut self) -> BoxFuture<'_, Result<OwnedFd>> {
+        Box::pin(async move {
+            let stream: &UnixStream = self.as_ref();
+
+            loop {
+                stream.readable().await?;
+
+                match stream.try_io(Interest::READABLE, || stream.as_raw_fd().recv_fd()) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    // Safety: the received descriptor is new and owned by us
+                    result => return result.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                }
+            }
+        })
+    }
+}
+
+impl TransportWrite for unix::OwnedWriteHalf {
+    fn supports_fd_passing(&self) -> bool {
+        true
+    }
+
+    fn send_fd(&mut self, fd: OwnedFd) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let stream: &UnixStream = self.as_ref();
+
+            // Sending doesn't close the local copy of the descriptor. It's closed when `fd` is dropped
+            loop {
+                stream.writable().await?;
+
+                match stream.try_io(Interest::WRITABLE, || {
+                    stream.as_raw_fd().send_fd(fd.as_raw_fd())
+                }) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    result => return result,
+                }
+            }
+        })
+    }
+}
+
+impl Transport for UnixStream {
+    type Read = unix::OwnedReadHalf;
+    type Write = unix::OwnedWriteHalf;
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        PeerCredentials::from_stream(self)
+    }
+
+    fn into_split(self) -> (Self::Read, Self::Write) {
+        UnixStream::into_split(self)
+    }
+}
+
+/// Transport half without FD passing support.
+/// Wraps any [tokio::io::AsyncRead] or [tokio::io::AsyncWrite]
+pub struct PlainHalf<T>(pub T);
+
+impl<T: AsyncRead + Unpin> AsyncRead for PlainHalf<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for PlainHalf<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl<T: AsyncRead + Send + Unpin> TransportRead for PlainHalf<T> {}
+
+impl<T: AsyncWrite + Send + Unpin> TransportWrite for PlainHalf<T> {}
+
+/// Any reader and writer pair
+impl<R, W> Transport for (R, W)
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    type Read = PlainHalf<R>;
+    type Write = PlainHalf<W>;
+
+    fn into_split(self) -> (Self::Read, Self::Write) {
+        (PlainHalf(self.0), PlainHalf(self.1))
+    }
+}
+
+impl Transport for TcpStream {
+    type Read = PlainHalf<tcp::OwnedReadHalf>;
+    type Write = PlainHalf<tcp::OwnedWriteHalf>;
+
+    fn into_split(self) -> (Self::Read, Self::Write) {
+        let (reader, writer) = TcpStream::into_split(self);
+        (PlainHalf(reader), PlainHalf(writer))
+    }
+}
+
+/// In-memory transport. See [tokio::io::duplex]
+impl Transport for DuplexStream {
+    type Read = PlainHalf<ReadHalf<DuplexStream>>;
+    type Write = PlainHalf<WriteHalf<DuplexStream>>;
+
+    fn into_split(self) -> (Self::Read, Self::Write) {
+        let (reader, writer) = tokio::io::split(self);
+        (PlainHalf(reader), PlainHalf(writer))
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use futures::channel::oneshot;
+use tokio::sync::Notify;
+
+use crate::{fd::TypedFd, message::RpcData, writer::WriteQueueMetrics};
+
+/// Outgoing write, queued for the writer task
+pub(crate) enum QueuedWrite {
+    /// Encoded message, followed by its file descriptors.
+    /// `call_id` is the id of the outgoing call or subscription, which fails if the frame isn't written
+    Frame {
+        frame: Vec<u8>,
+        fds: Vec<TypedFd>,
+        call_id: Option<i64>,
+    },
+    /// Flush marker. Resolved after all previously queued writes are written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Outgoing write priority
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Priority {
+    /// Responses to the peer requests. Written before new requests
+    High,
+    Normal,
+}
+
+impl Priority {
+    pub fn of(data: &RpcData) -> Self {
+        match data {
+            RpcData::Response(_) | RpcData::FdResponse { .. } | RpcData::EndOfStream => Self::High,
+            // Cancellations are kept in order with the calls they cancel
+            _ => Self::Normal,
+        }
+    }
+}
+
+struct State {
+    high: VecDeque<QueuedWrite>,
+    normal: VecDeque<QueuedWrite>,
+    /// Max number of queued writes since the queue creation
+    peak_depth: usize,
+    closed: bool,
+    /// Writer task failed to write a message. Reset when the connection is replaced
+    failed: bool,
+    /// Writer task is running. Unset when the task finishes after the queue is closed
+    receiver_active: bool,
+}
+
+impl State {
+    fn depth(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    /// Notifies blocked writers about free space
+    space: Notify,
+    /// Notifies the writer task about new items
+    items: Notify,
+}
+
+/// Make a bounded write queue with a given `capacity`
+pub(crate) fn queue(capacity: usize) -> (WriteQueue, WriteQueueReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            high: VecDeque::new(),
+            normal: VecDeque::new(),
+            peak_depth: 0,
+            closed: false,
+            failed: false,
+            receiver_active: true,
+        }),
+        capacity: capacity.max(1),
+        space: Notify::new(),
+        items: Notify::new(),
+    });
+
+    (
+        WriteQueue {
+            shared: shared.clone(),
+        },
+        WriteQueueReceiver { shared },
+    )
+}
+
+/// Sending half of the write queue, shared by the writer handles. Closes the queue when dropped
+pub(crate) struct WriteQueue {
+    shared: Arc<Shared>,
+}
+
+impl WriteQueue {
+    /// Push a write into the queue. Waits for free space if the queue is full.
+    /// Flush markers are queued regardless of the capacity.
+    /// Returns `false` if the queue has been closed, or the writer task has failed to write
+    pub async fn push(&self, item: QueuedWrite, priority: Priority) -> bool {
+        let mut item = Some(item);
+
+        loop {
+            let notified = self.shared.space.notified();
+
+            {
+                let mut state = self.shared.state.lock().unwrap();
+
+                if state.closed || state.failed {
+                    return false;
+                }
+
+                let is_flush = matches!(item, Some(QueuedWrite::Flush(_)));
+                if is_flush || state.depth() < self.shared.capacity {
+                    // Item is taken only once, right before returning
+                    let item = item.take().unwrap();
+                    match priority {
+                        Priority::High => state.high.push_back(item),
+                        Priority::Normal => state.normal.push_back(item),
+                    }
+
+                    state.peak_depth = state.peak_depth.max(state.depth());
+                    drop(state);
+
+                    self.shared.items.notify_one();
+                    return true;
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Drop pending writes, reset failure, and reopen the queue if closed.
+    /// Used when the connection is replaced.
+    /// Returns a new receiver if the writer task has finished, and should be restarted
+    pub fn reset(&self) -> Option<WriteQueueReceiver> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.high.clear();
+        state.normal.clear();
+        state.failed = false;
+        state.closed = false;
+
+        let restart = !state.receiver_active;
+        state.receiver_active = true;
+        drop(state);
+
+        self.shared.space.notify_waiters();
+
+        restart.then(|| WriteQueueReceiver {
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Close the queue. Writes, which are already queued, are still delivered to the writer task
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+
+        self.shared.items.notify_one();
+        self.shared.space.notify_waiters();
+    }
+
+    pub fn metrics(&self) -> WriteQueueMetrics {
+        let state = self.shared.state.lock().unwrap();
+
+        WriteQueueMetrics {
+            depth: state.depth(),
+            high_priority_depth: state.high.len(),
+            peak_depth: state.peak_depth,
+            capacity: self.shared.capacity,
+        }
+    }
+}
+
+impl Drop for WriteQueue {
+    fn drop(&mut self) {
+        self.close()
+    }
+}
+
+/// Receiving half of the write queue, owned by the writer task
+pub(crate) struct WriteQueueReceiver {
+    shared: Arc<Shared>,
+}
+
+impl WriteQueueReceiver {
+    /// Mark the queue failed after a write error. Pending writes are dropped,
+    /// and new writes are rejected until the queue is reset.
+    /// Returns ids of the calls, which frames have been dropped
+    pub fn fail(&self) -> Vec<i64> {
+        let mut guard = self.shared.state.lock().unwrap();
+        let state = &mut *guard;
+
+        let dropped = state
+            .high
+            .drain(..)
+            .chain(state.normal.drain(..))
+            .filter_map(|item| match item {
+                QueuedWrite::Frame { call_id, .. } => call_id,
+                QueuedWrite::Flush(_) => None,
+            })
+            .collect();
+        state.failed = true;
+        drop(guard);
+
+        self.shared.space.notify_waiters();
+        dropped
+    }
+
+    /// Pop next write, preferring high priority writes.
+    /// Returns `None` if the queue is closed and empty
+    pub async fn pop(&self) -> Option<QueuedWrite> {
+        loop {
+            let notified = self.shared.items.notified();
+
+            {
+                let mut state = self.shared.state.lock().unwrap();
+
+                if let Some(item) = state.high.pop_front().or_else(|| state.normal.pop_front()) {
+                    drop(state);
+                    self.shared.space.notify_one();
+
+                    return Some(item);
+                } else if state.closed {
+                    state.receiver_active = false;
+                    return None;
+                }
+            }
+
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{queue, Priority, QueuedWrite};
+
+    fn frame(byte: u8) -> QueuedWrite {
+        QueuedWrite::Frame {
+            frame: vec![byte],
+            fds: Vec::new(),
+            call_id: None,
+        }
+    }
+
+    async fn pop_frame(receiver: &super::WriteQueueReceiver) -> Option<u8> {
+        match receiver.pop().await? {
+            QueuedWrite::Frame { frame, .. } => Some(frame[0]),
+            QueuedWrite::Flush(_) => panic!("Unexpected flush marker"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priorities() {
+        let (sender, receiver) = queue(4);
+
+        assert!(sender.push(frame(0), Priority::Normal).await);
+        assert!(sender.push(frame(1), Priority::Normal).await);
+        assert!(sender.push(frame(2), Priority::High).await);
+        assert!(sender.push(frame(3), Priority::High).await);
+
+        let metrics = sender.metrics();
+        assert_eq!(metrics.depth, 4);
+        assert_eq!(metrics.high_priority_depth, 2);
+
+        assert_eq!(pop_frame(&receiver).await, Some(2));
+        assert_eq!(pop_frame(&receiver).await, Some(3));
+        assert_eq!(pop_frame(&receiver).await, Some(0));
+
+        // Queued writes are delivered after the queue is closed
+        drop(sender);
+        assert_eq!(pop_frame(&receiver).await, Some(1));
+        assert_eq!(pop_frame(&receiver).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let (sender, receiver) = queue(1);
+
+        assert!(sender.push(frame(0), Priority::Normal).await);
+
+        let push = tokio::spawn(async move {
+            let result = sender.push(frame(1), Priority::High).await;
+            (sender, result)
+        });
+
+        assert_eq!(pop_frame(&receiver).await, Some(0));
+
+        let (sender, result) = push.await.unwrap();
+        assert!(result);
+        assert_eq!(sender.metrics().peak_depth, 1);
+
+        // Failed queue rejects writes until reset
+        receiver.fail();
+        assert!(!sender.push(frame(2), Priority::Normal).await);
+
+        assert!(sender.reset().is_none());
+        assert!(sender.push(frame(3), Priority::Normal).await);
+
+        sender.close();
+        assert!(!sender.push(frame(4), Priority::Normal).await);
+    }
+
+    #[tokio::test]
+    async fn test_failed_calls() {
+        let (sender, receiver) = queue(4);
+
+        for call_id in [Some(1), None, Some(2)] {
+            let item = QueuedWrite::Frame {
+                frame: vec![0],
+                fds: Vec::new(),
+                call_id,
+            };
+            assert!(sender.push(item, Priority::Normal).await);
+        }
+
+        assert_eq!(receiver.fail(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let (sender, receiver) = queue(1);
+
+        // Running writer task is reused
+        sender.close();
+        assert!(sender.reset().is_none());
+        assert!(sender.push(frame(0), Priority::Normal).await);
+        assert_eq!(pop_frame(&receiver).await, Some(0));
+
+        // Finished writer task is restarted
+        sender.close();
+        assert_eq!(pop_frame(&receiver).await, None);
+
+        let receiver = sender.reset().unwrap();
+        assert!(sender.push(frame(1), Priority::Normal).await);
+        assert_eq!(pop_frame(&receiver).await, Some(1));
+    }
+}
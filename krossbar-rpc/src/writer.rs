@@ -1,23 +1,144 @@
-use std::{os::fd::AsRawFd, pin::Pin, sync::Arc};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, Instant},
+};
 
-use async_send_fd::AsyncSendTokioStream;
-use futures::{lock::Mutex, stream::FusedStream, Future, FutureExt as _, StreamExt as _};
+use futures::{
+    channel::oneshot::{self, Receiver as OneReceiver},
+    lock::Mutex,
+    Future,
+};
 use log::{debug, trace, warn};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::{
-    io::AsyncWriteExt,
-    net::{unix::OwnedWriteHalf, UnixStream},
+use tokio::sync::{watch, RwLock};
+
+use crate::{
+    codec::Codec,
+    fd::TypedFd,
+    handshake::Hello,
+    shm,
+    socket_writer::{BufferPool, SocketWriter},
+    subscription::{Subscription, SubscriptionOptions},
+    transport::{PeerCredentials, TransportWrite},
+    write_queue::{self, Priority, QueuedWrite, WriteQueue, WriteQueueReceiver},
 };
 
-use crate::message_stream::AsyncWriteMessage;
-
 use super::{
     calls_registry::CallsRegistry,
     message::{self, RpcMessage},
 };
 
 type CallResultType<T> = crate::Result<Pin<Box<dyn Future<Output = crate::Result<T>> + Send>>>;
-type SubResultType<T> = crate::Result<Pin<Box<dyn FusedStream<Item = crate::Result<T>> + Send>>>;
+type SubResultType<T> = crate::Result<Subscription<T>>;
+
+/// Outgoing call options
+#[derive(Clone, Debug, Default)]
+pub struct CallOptions {
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    replay: bool,
+}
+
+impl CallOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail the call with [crate::Error::Timeout] if no response received within `timeout`
+    /// after the call is made. Overrides default [crate::rpc::Rpc] call timeout
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fail the call with [crate::Error::Timeout] if no response received until `deadline`.
+    /// If both timeout and deadline are set, the earliest one is used
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Resend the call with the same message id if the connection is replaced using
+    /// [crate::rpc::Rpc::on_reconnected] before the call is resolved.
+    /// Otherwise pending calls fail with [crate::Error::PeerDisconnected] when the connection is lost.
+    /// Replayed calls fail if the [crate::rpc::Rpc] is dropped.
+    /// Use only for idempotent calls, because the peer may receive the call twice
+    pub fn replay(mut self, replay: bool) -> Self {
+        self.replay = replay;
+        self
+    }
+}
+
+/// Connection state, which can be watched using [RpcWriter::watch_connection_state]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Initial connection is alive
+    Connected,
+    /// Connection has been lost or closed
+    Disconnected,
+    /// Connection has been lost, and [crate::reconnect::ReconnectingRpc] performs
+    /// reconnection `attempt`, starting from 1
+    Reconnecting { attempt: u32 },
+    /// Connection has been replaced using [crate::rpc::Rpc::on_reconnected].
+    /// `generation` is incremented on every reconnection, starting from 1
+    Reconnected { generation: u64 },
+}
+
+/// Default batch size, which triggers immediate write: 64KB
+pub const DEFAULT_BATCH_SIZE: usize = 64 * 1024;
+
+/// Max time to write pending messages when the connection is closed
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Outgoing messages batching options. See [crate::rpc::Rpc::with_write_batching]
+#[derive(Clone, Debug)]
+pub struct BatchOptions {
+    pub(crate) max_size: usize,
+    pub(crate) flush_delay: Duration,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_BATCH_SIZE,
+            flush_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl BatchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write pending messages as soon as their total size reaches `max_size`.
+    /// Default is [DEFAULT_BATCH_SIZE]
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Write pending messages after `delay` since the first message in a batch.
+    /// Zero delay writes pending messages as soon as the writing task yields. Default is zero
+    pub fn flush_delay(mut self, delay: Duration) -> Self {
+        self.flush_delay = delay;
+        self
+    }
+}
+
+/// Outgoing write queue metrics. See [crate::rpc::Rpc::with_writer_task]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteQueueMetrics {
+    /// Number of queued writes
+    pub depth: usize,
+    /// Number of queued responses, which are written before other messages
+    pub high_priority_depth: usize,
+    /// Max number of queued writes since the queue creation
+    pub peak_depth: usize,
+    /// Queue capacity
+    pub capacity: usize,
+}
 
 /// A writer to make RPC calls, or subscribe to the client
 #[derive(Clone)]
@@ -26,56 +147,217 @@ pub struct RpcWriter {
     /// Peer name
     peer_name: String,
     /// Writer part of the socket
-    socket: Arc<Mutex<OwnedWriteHalf>>,
+    socket: Arc<Mutex<SocketWriter>>,
     /// Call registry to add outgoing calls into for later resolve
-    registry: Arc<Mutex<CallsRegistry>>,
+    registry: Arc<CallsRegistry>,
+    /// Default call timeout. Used if a call doesn't specify its own
+    default_timeout: Option<Duration>,
+    /// Connection state shared between all writer handles
+    state: Arc<watch::Sender<ConnectionState>>,
+    /// Outgoing write queue if the writes are made by a dedicated writer task
+    queue: Option<Arc<WriteQueue>>,
+    /// Outgoing messages codec
+    codec: Codec,
+    /// Reusable buffers to encode outgoing messages into
+    buffers: BufferPool,
+    /// Peer process credentials. Updated on reconnect
+    credentials: Arc<SyncMutex<Option<PeerCredentials>>>,
+    /// Min encoded message size to send the message using shared memory.
+    /// Shared between the writer handles, because it's updated on reconnect
+    shared_memory_threshold: Arc<SyncMutex<Option<usize>>>,
+    /// Held shared while a resendable message is registered and sent for the first time, and
+    /// exclusively while resending on reconnect. Keeps a message from being sent twice into
+    /// the same connection
+    resend_lock: Arc<RwLock<()>>,
 }
 
 impl RpcWriter {
     /// Make a new writer from a reading half of the stream
     pub(crate) fn new(
-        socket: OwnedWriteHalf,
-        registry: Arc<Mutex<CallsRegistry>>,
+        socket: Box<dyn TransportWrite>,
+        registry: Arc<CallsRegistry>,
         name: &str,
+        credentials: Option<PeerCredentials>,
     ) -> Self {
+        let socket = SocketWriter::new(socket);
+        let buffers = socket.buffers().clone();
+
         Self {
             peer_name: name.to_owned(),
             socket: Arc::new(Mutex::new(socket)),
             registry,
+            default_timeout: None,
+            state: Arc::new(watch::Sender::new(ConnectionState::Connected)),
+            queue: None,
+            codec: Codec::default(),
+            buffers,
+            credentials: Arc::new(SyncMutex::new(credentials)),
+            shared_memory_threshold: Arc::new(SyncMutex::new(None)),
+            resend_lock: Arc::new(RwLock::new(())),
         }
     }
 
+    /// Set default call timeout
+    pub(crate) fn set_default_timeout(&mut self, timeout: Option<Duration>) {
+        self.default_timeout = timeout;
+    }
+
+    /// Set verbose peer name. Used when the name is received in a handshake
+    pub(crate) fn set_peer_name(&mut self, name: &str) {
+        self.peer_name = name.to_owned();
+    }
+
+    /// Write handshake hello. Hello is always encoded as BSON
+    pub(crate) async fn write_hello(&self, hello: &Hello) -> std::io::Result<()> {
+        let frame = Codec::Bson
+            .encode(hello)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let mut socket_lock = self.socket.lock().await;
+        socket_lock.write_frame(frame).await?;
+        socket_lock.flush().await
+    }
+
+    /// Set outgoing messages codec
+    pub(crate) fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Enable outgoing messages batching
+    pub(crate) fn set_batching(&mut self, options: BatchOptions) {
+        match self.socket.try_lock() {
+            Some(mut socket) => socket.set_batching(options),
+            None => warn!("Failed to enable write batching: the writer is in use"),
+        }
+    }
+
+    /// Send messages larger than `threshold` bytes using shared memory, or disable
+    /// shared memory if `None`. Expects the transport to support FD passing
+    pub(crate) fn set_shared_memory(&self, threshold: Option<usize>) {
+        *self.shared_memory_threshold.lock().unwrap() = threshold;
+    }
+
+    /// If the connection transport supports FD passing.
+    /// Returns `None` if the writer is in use. Used to configure the writer
+    pub(crate) fn try_supports_fd_passing(&self) -> Option<bool> {
+        self.socket
+            .try_lock()
+            .map(|socket| socket.supports_fd_passing())
+    }
+
+    /// Spawn a writer task, which owns writing half of the socket.
+    /// Writer handles push outgoing messages into a queue of `capacity` messages
+    pub(crate) fn start_writer_task(&mut self, capacity: usize) {
+        let (queue, receiver) = write_queue::queue(capacity);
+
+        self.spawn_writer_task(receiver);
+        self.queue = Some(Arc::new(queue));
+    }
+
+    fn spawn_writer_task(&self, receiver: WriteQueueReceiver) {
+        tokio::spawn(Self::writer_task(
+            receiver,
+            self.socket.clone(),
+            self.state.clone(),
+            self.registry.clone(),
+        ));
+    }
+
+    /// Outgoing write queue metrics. Returns `None` if the writer task is not enabled.
+    /// See [crate::rpc::Rpc::with_writer_task]
+    pub fn write_queue_metrics(&self) -> Option<WriteQueueMetrics> {
+        self.queue.as_ref().map(|queue| queue.metrics())
+    }
+
     /// Verbose peer name
     pub fn peer_name(&self) -> &str {
         &self.peer_name
     }
 
+    /// Peer process credentials. Available only for [tokio::net::UnixStream] connections
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        *self.credentials.lock().unwrap()
+    }
+
+    /// Current connection state
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Watch connection state changes
+    pub fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Mark the connection as disconnected. Notifies watchers only if the state has changed
+    pub(crate) fn set_disconnected(&self) {
+        self.state.send_if_modified(|state| {
+            let modified = *state != ConnectionState::Disconnected;
+            *state = ConnectionState::Disconnected;
+            modified
+        });
+    }
+
+    /// Mark the connection as being restored by `attempt`
+    pub(crate) fn set_reconnecting(&self, attempt: u32) {
+        self.state
+            .send_replace(ConnectionState::Reconnecting { attempt });
+    }
+
+    /// Mark the connection as disconnected after a failed write.
+    /// Failed writes during reconnection don't reset [ConnectionState::Reconnecting]
+    fn mark_disconnected(state: &watch::Sender<ConnectionState>) {
+        state.send_if_modified(|state| {
+            let modified = matches!(
+                *state,
+                ConnectionState::Connected | ConnectionState::Reconnected { .. }
+            );
+            if modified {
+                *state = ConnectionState::Disconnected;
+            }
+            modified
+        });
+    }
+
     /// Replace writer stream with a new handle if reconnected.
     /// Existing handles will remain valid, and can be used to send data
-    pub(crate) async fn on_reconnected(&mut self, other: RpcWriter) {
-        let RpcWriter { socket, .. } = other;
+    pub(crate) async fn on_reconnected(&mut self, other: RpcWriter, generation: u64) {
+        let RpcWriter {
+            socket,
+            credentials,
+            ..
+        } = other;
 
         let socket = Arc::into_inner(socket).unwrap().into_inner();
 
-        trace!(
-            "Writer reconnected. New socket: <{}>",
-            socket.as_ref().as_raw_fd()
-        );
+        trace!("Writer to {} reconnected", self.peer_name);
+
+        // Wait for the first sends in progress. New ones are either resent below,
+        // or written after the stream is replaced
+        let _resend_guard = self.resend_lock.write().await;
 
-        *self.socket.lock().await = socket;
+        self.socket.lock().await.replace_stream(socket);
+        *self.credentials.lock().unwrap() = *credentials.lock().unwrap();
+
+        // Queued writes belong to the old connection.
+        // Writer task has finished if the connection has been closed
+        if let Some(ref queue) = self.queue {
+            if let Some(receiver) = queue.reset() {
+                self.spawn_writer_task(receiver);
+            }
+        }
 
-        let mut registry_lock = self.registry.lock().await;
         // Clear pending calls as we're not going to receive responses
-        registry_lock.clear_pending_calls();
+        self.registry.clear_pending_calls();
 
-        debug!("Resending active subscriptions");
+        debug!("Resending active subscriptions and replayed calls");
 
-        for (message_id, data) in registry_lock.active_subscriptions() {
-            debug!("Sending subsription {message_id}: {data:?}");
+        for (message_id, data) in self.registry.resend_calls() {
+            debug!("Resending call {message_id}: {data:?}");
 
             let message = RpcMessage {
-                id: *message_id,
-                data: data.clone(),
+                id: message_id,
+                data,
             };
 
             // In case we failed to send immediately send error response
@@ -83,6 +365,9 @@ impl RpcWriter {
                 warn!("Failed to resent persisten call to a client: {e:?}")
             }
         }
+
+        self.state
+            .send_replace(ConnectionState::Reconnected { generation });
     }
 
     /// Send one-way mesage
@@ -100,7 +385,7 @@ impl RpcWriter {
             },
         };
 
-        if let Err(e) = Box::pin(self.socket_write_and_monitor(&message, true)).await {
+        if let Err(e) = Box::pin(self.socket_write_and_monitor(&message, Vec::new(), true)).await {
             debug!("Error sending a message: {e:?}");
 
             Err(crate::Error::PeerDisconnected)
@@ -110,148 +395,196 @@ impl RpcWriter {
     }
 
     /// Make a client call
-    /// Immediately returns an `Error` if `P` doesn't serialize into Bson, or the client has disconnected
+    /// Immediately returns an `Error` if `P` doesn't serialize into Bson, or the client has disconnected.
+    /// Uses default [crate::rpc::Rpc] call timeout if set
     pub async fn call<P: Serialize, R: DeserializeOwned>(
         &self,
         endpoint: &str,
         data: &P,
+    ) -> CallResultType<R> {
+        self.call_with_options(endpoint, data, &CallOptions::default())
+            .await
+    }
+
+    /// Make a client call with custom call options
+    /// Immediately returns an `Error` if `P` doesn't serialize into Bson, or the client has disconnected
+    pub async fn call_with_options<P: Serialize, R: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        data: &P,
+        options: &CallOptions,
     ) -> CallResultType<R> {
         let data = bson::to_bson(data).map_err(|e| crate::Error::ParamsTypeError(e.to_string()))?;
-        let (id, result) = self.registry.lock().await.add_call();
+        let deadline = self.call_deadline(options);
+        let message_data = message::RpcData::Call {
+            endpoint: endpoint.to_owned(),
+            params: data,
+        };
+
+        let _resend_guard = if options.replay {
+            Some(self.resend_lock.read().await)
+        } else {
+            None
+        };
+
+        let (id, result) = self
+            .registry
+            .add_call(options.replay.then(|| message_data.clone()));
 
-        debug!("New {id} call to an {endpoint}: {data:?}");
+        debug!("New {id} call to an {endpoint}: {message_data:?}");
 
         let message = RpcMessage {
             id,
-            data: message::RpcData::Call {
-                endpoint: endpoint.to_owned(),
-                params: data,
-            },
+            data: message_data,
         };
 
         // In case we failed to send immediately send error response
-        if let Err(_) = self.socket_write(&message).await {
+        if self.socket_write(&message).await.is_err() {
+            self.registry.remove(id);
             return Err(crate::Error::PeerDisconnected);
         }
 
-        Ok(Box::pin(result.map(|chan_result| {
-            match chan_result {
-                Ok(data) => data.and_then(|response| match bson::from_bson(response) {
-                    Ok(value) => Ok(value),
-                    Err(e) => Err(crate::Error::ResultTypeError(e.to_string())),
-                }),
-                // Channel disconnected
-                Err(_) => Err(crate::Error::PeerDisconnected),
-            }
-        })))
+        Ok(self.wait_response(id, result, deadline, |response| {
+            bson::from_bson(response).map_err(|e| crate::Error::ResultTypeError(e.to_string()))
+        }))
     }
 
-    /// Make a call with FD. Used by the hub to send peer FD's
-    /// Immediately returns an `Error` if `P` doesn't serialize into Bson, or the client has disconnected
+    /// Make a call, which is responded with a list of FDs. Used by the hub to send peer FD's.
+    /// Use [TypedFd::peer_credentials] to get credentials of a received stream peer
+    /// Immediately returns an `Error` if `P` doesn't serialize into Bson, the client has disconnected,
+    /// or the transport doesn't support FD passing.
+    /// Uses default [crate::rpc::Rpc] call timeout if set
     pub async fn call_fd<P: Serialize, R: DeserializeOwned>(
         &self,
         endpoint: &str,
         data: &P,
-    ) -> CallResultType<(R, UnixStream)> {
+    ) -> CallResultType<(R, Vec<TypedFd>)> {
+        self.call_fd_with_options(endpoint, data, &CallOptions::default())
+            .await
+    }
+
+    /// Make a call with FD with custom call options
+    /// Immediately returns an `Error` if `P` doesn't serialize into Bson, or the client has disconnected
+    pub async fn call_fd_with_options<P: Serialize, R: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        data: &P,
+        options: &CallOptions,
+    ) -> CallResultType<(R, Vec<TypedFd>)> {
         let data = bson::to_bson(data).map_err(|e| crate::Error::ParamsTypeError(e.to_string()))?;
-        let (id, result) = self.registry.lock().await.add_fd_call();
 
-        debug!("New {id} FD call to the {endpoint}: {data:?}");
+        if !self.supports_fd_passing().await {
+            return Err(crate::Error::NotSupported);
+        }
+
+        let deadline = self.call_deadline(options);
+        let message_data = message::RpcData::Call {
+            endpoint: endpoint.to_owned(),
+            params: data,
+        };
+
+        let _resend_guard = if options.replay {
+            Some(self.resend_lock.read().await)
+        } else {
+            None
+        };
+
+        let (id, result) = self
+            .registry
+            .add_fd_call(options.replay.then(|| message_data.clone()));
+
+        debug!("New {id} FD call to the {endpoint}: {message_data:?}");
 
         let message = RpcMessage {
             id,
-            data: message::RpcData::Call {
-                endpoint: endpoint.to_owned(),
-                params: data,
-            },
+            data: message_data,
         };
 
         // In case we failed to send immediately send error response
         if let Err(e) = self.socket_write(&message).await {
             debug!("Error making an FD call: {e:?}");
 
+            self.registry.remove(id);
             return Err(crate::Error::PeerDisconnected);
         }
 
-        Ok(Box::pin(result.map(|chan_result| {
-            match chan_result {
-                Ok(data) => data.and_then(|response| match bson::from_bson(response.0) {
-                    Ok(value) => Ok((value, response.1)),
-                    Err(e) => Err(crate::Error::ResultTypeError(e.to_string())),
-                }),
-                // Channel disconnected
-                Err(_) => Err(crate::Error::PeerDisconnected),
-            }
-        })))
+        Ok(self.wait_response(
+            id,
+            result,
+            deadline,
+            |(response, fds)| match bson::from_bson(response) {
+                Ok(value) => Ok((value, fds)),
+                Err(e) => Err(crate::Error::ResultTypeError(e.to_string())),
+            },
+        ))
     }
 
     /// Subscribe to the `endpoint`
-    /// Immediately returns an `Error` if the client has disconnected
+    /// Immediately returns an `Error` if the client has disconnected.
+    /// Dropping returned stream unsubscribes from the peer
     pub async fn subscribe<R: DeserializeOwned>(&self, endpoint: &str) -> SubResultType<R> {
-        let mut registry_lock = self.registry.lock().await;
-
-        let (id, result) = registry_lock.add_subscription();
-
-        debug!("New subscription with id {id} to the {endpoint}");
+        self.subscribe_with_options(endpoint, &SubscriptionOptions::default())
+            .await
+    }
 
+    /// Subscribe to the `endpoint` with custom buffer size and overflow policy
+    /// Immediately returns an `Error` if the client has disconnected.
+    /// Dropping returned stream unsubscribes from the peer
+    pub async fn subscribe_with_options<R: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        options: &SubscriptionOptions,
+    ) -> SubResultType<R> {
         let data = message::RpcData::Subscription {
             endpoint: endpoint.to_owned(),
         };
-        let message = RpcMessage {
-            id,
-            data: data.clone(),
-        };
 
-        // Add persistent call to resubscribe on reconnect.
-        registry_lock.add_persistent_call(id, data);
+        let _resend_guard = self.resend_lock.read().await;
+
+        // Subscription data is kept to resubscribe on reconnect
+        let (id, result) = self.registry.add_subscription(options, data.clone());
+
+        debug!("New subscription with id {id} to the {endpoint}");
+
+        let message = RpcMessage { id, data };
 
         // In case we failed to send immediately send error response
         if let Err(e) = self.socket_write(&message).await {
             debug!("Error subscribing to a client: {e:?}");
 
+            self.registry.remove(id);
             return Err(crate::Error::PeerDisconnected);
         }
 
-        Ok(Box::pin(result.map(|chan_result| {
-            chan_result.and_then(|response| match bson::from_bson(response) {
-                Ok(value) => Ok(value),
-                Err(e) => Err(crate::Error::ResultTypeError(e.to_string())),
-            })
-        })))
+        Ok(Subscription::new(id, result, self.clone()))
     }
 
-    /// Make a connection request. Blocks until a connection response is received
-    /// Immediately returns an `Error` if the client has disconnected
+    /// Make a connection request, passing `fds` to the peer. Usually a single [crate::fd::FdKind::UnixStream].
+    /// Blocks until a connection response is received
+    /// Immediately returns an `Error` if the client has disconnected, or the transport doesn't support FD passing
     pub async fn connection_request(
         &self,
         client_name: &str,
         target_name: &str,
-        socket: UnixStream,
+        fds: Vec<TypedFd>,
     ) -> crate::Result<()> {
+        if !self.supports_fd_passing().await {
+            return Err(crate::Error::NotSupported);
+        }
+
         let message = RpcMessage {
             id: 0,
             data: message::RpcData::ConnectionRequest {
                 client_name: client_name.into(),
                 target_name: target_name.into(),
+                fds: fds.iter().map(TypedFd::kind).collect(),
             },
         };
 
         debug!("New connection request from {client_name} to {target_name}");
 
-        if let Err(e) = self.socket_write(&message).await {
-            debug!(
-                "Failed to send connection request message: {}",
-                e.to_string()
-            );
-
-            return Err(crate::Error::PeerDisconnected);
-        }
-
-        if let Err(e) = self.socket.lock().await.send_stream(socket).await {
-            debug!(
-                "Failed to send connection request socket: {}",
-                e.to_string()
-            );
+        if let Err(e) = self.socket_write_with_fds(&message, fds).await {
+            debug!("Failed to send connection request: {e}");
 
             return Err(crate::Error::PeerDisconnected);
         }
@@ -281,69 +614,468 @@ impl RpcWriter {
         true
     }
 
-    /// Respond to a call with FD
+    /// Close subscription stream
+    /// Returns `true` if succesfully sent
+    pub async fn end_subscription(&self, message_id: i64) -> bool {
+        debug!("Closing subscription {message_id}");
+
+        let message = RpcMessage {
+            id: message_id,
+            data: message::RpcData::EndOfStream,
+        };
+
+        if self.socket_write(&message).await.is_err() {
+            debug!("Failed to write subscription end");
+            return false;
+        }
+
+        true
+    }
+
+    /// Respond to a call with a list of FDs
     /// Returns `true` if succesfully responded
     pub async fn respond_with_fd<P: Serialize>(
         &self,
         message_id: i64,
         data: crate::Result<P>,
-        stream: UnixStream,
+        fds: Vec<TypedFd>,
     ) -> bool {
         let data = data.and_then(|value| {
             bson::to_bson(&value).map_err(|e| crate::Error::ResultTypeError(e.to_string()))
         });
 
-        debug!("Responding to {message_id} with FD and {data:?}");
+        debug!(
+            "Responding to {message_id} with {} FDs and {data:?}",
+            fds.len()
+        );
+
+        if !self.supports_fd_passing().await {
+            debug!("Transport doesn't support FD passing");
+            return false;
+        }
 
         let message = RpcMessage {
             id: message_id,
-            data: message::RpcData::FdResponse(data),
+            data: message::RpcData::FdResponse {
+                result: data,
+                fds: fds.iter().map(TypedFd::kind).collect(),
+            },
         };
 
-        if let Err(e) = self.socket_write(&message).await {
-            debug!("Failed to write client response: {}", e.to_string());
-            return false;
-        } else if let Err(e) = self.socket.lock().await.send_stream(stream).await {
-            debug!("Failed to send fd to a client: {}", e.to_string());
+        if let Err(e) = self.socket_write_with_fds(&message, fds).await {
+            debug!("Failed to write client response with FD: {e}");
             return false;
         }
 
         true
     }
 
-    /// Flushes the writer sending all pending data. This is useful when you're going to drop the connection
-    /// to ensure all message responses are sent
+    /// If the connection transport supports FD passing
+    pub async fn supports_fd_passing(&self) -> bool {
+        self.socket.lock().await.supports_fd_passing()
+    }
+
+    /// Flushes the writer sending all pending data, including batched messages.
+    /// This is useful when you're going to drop the connection to ensure all message responses are sent
     pub async fn flush(&self) {
-        let _ = self.socket.lock().await.flush().await;
+        match self.queue {
+            Some(ref queue) => {
+                // Writer task flushes the socket after writing all preceding messages
+                let (sender, receiver) = oneshot::channel();
+
+                if queue
+                    .push(QueuedWrite::Flush(sender), Priority::Normal)
+                    .await
+                {
+                    let _ = receiver.await;
+                }
+            }
+            None => {
+                let _ = self.socket.lock().await.flush().await;
+            }
+        }
+    }
+
+    /// Shutdown writing half of the connection.
+    /// Gives up writing pending messages after [SHUTDOWN_TIMEOUT] if the peer doesn't read them
+    pub(crate) async fn shutdown(&self) {
+        let shutdown = async {
+            if let Some(ref queue) = self.queue {
+                self.flush().await;
+                queue.close();
+            }
+
+            let _ = self.socket.lock().await.shutdown().await;
+        };
+
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Failed to write pending messages to {} in {SHUTDOWN_TIMEOUT:?}",
+                self.peer_name
+            );
+
+            if let Some(ref queue) = self.queue {
+                queue.close();
+            }
+        }
+    }
+
+    /// Calculate call deadline using call `options` and default call timeout
+    fn call_deadline(&self, options: &CallOptions) -> Option<Instant> {
+        let timeout_deadline = options
+            .timeout
+            .or(self.default_timeout)
+            .map(|timeout| Instant::now() + timeout);
+
+        match (timeout_deadline, options.deadline) {
+            (Some(timeout), Some(deadline)) => Some(timeout.min(deadline)),
+            (timeout, deadline) => timeout.or(deadline),
+        }
+    }
+
+    /// Make a future, which waits for a call response until the `deadline`.
+    /// If deadline is reached, cancels the call and returns [crate::Error::Timeout].
+    /// If the future is dropped before the call is resolved, the call is cancelled
+    fn wait_response<T, R, F>(
+        &self,
+        id: i64,
+        response: OneReceiver<crate::Result<T>>,
+        deadline: Option<Instant>,
+        map: F,
+    ) -> Pin<Box<dyn Future<Output = crate::Result<R>> + Send>>
+    where
+        T: Send + 'static,
+        F: FnOnce(T) -> crate::Result<R> + Send + 'static,
+    {
+        let mut guard = CallGuard {
+            id,
+            writer: Some(self.clone()),
+        };
+
+        Box::pin(async move {
+            let chan_result = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline.into(), response).await {
+                    Ok(chan_result) => chan_result,
+                    Err(_) => {
+                        debug!("Call {id} timed out");
+
+                        if let Some(writer) = guard.writer.take() {
+                            writer.cancel(id, message::RpcData::Cancel).await;
+                        }
+                        return Err(crate::Error::Timeout);
+                    }
+                },
+                None => response.await,
+            };
+
+            // Call resolved. Nothing to cancel
+            guard.disarm();
+
+            match chan_result {
+                Ok(data) => data.and_then(map),
+                // Channel disconnected
+                Err(_) => Err(crate::Error::PeerDisconnected),
+            }
+        })
+    }
+
+    /// Remove pending call or subscription from the registry and notify the peer.
+    /// `data` is either [message::RpcData::Cancel], or [message::RpcData::Unsubscribe]
+    pub(crate) async fn cancel(&self, id: i64, data: message::RpcData) {
+        self.registry.remove(id);
+
+        debug!("Cancelling call {id}: {data:?}");
+
+        let message = RpcMessage { id, data };
+
+        if let Err(e) = self.socket_write(&message).await {
+            debug!("Failed to send call cancellation: {e:?}");
+        }
+    }
+
+    /// Cancel the call from a synchronous context, e.g. `drop`.
+    /// Removes the call from the registry immediately if possible, and sends
+    /// cancellation message in background
+    pub(crate) fn cancel_in_background(self, id: i64, data: message::RpcData) {
+        self.registry.remove(id);
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move { self.cancel(id, data).await });
+            }
+            Err(_) => warn!("Call {id} cancelled outside of Tokio runtime. Can't notify the peer"),
+        }
     }
 
     async fn socket_write(&self, message: &RpcMessage) -> crate::Result<()> {
-        self.socket_write_and_monitor(message, false).await
+        self.socket_write_and_monitor(message, Vec::new(), false)
+            .await
     }
 
-    /// Write message into a socket and monitor
+    /// Write message followed by the `fds`.
+    /// No other message gets between the message and its FDs.
+    /// Pending batched messages are written before the FDs
+    async fn socket_write_with_fds(
+        &self,
+        message: &RpcMessage,
+        fds: Vec<TypedFd>,
+    ) -> crate::Result<()> {
+        self.socket_write_and_monitor(message, fds, false).await
+    }
+
+    /// Write message and its FDs into a socket and monitor.
+    /// If the writer task is enabled, the message is pushed into the write queue.
+    /// Messages larger than the shared memory threshold are sent using a memfd,
+    /// see [RpcWriter::encode_shared]
     async fn socket_write_and_monitor(
         &self,
         message: &RpcMessage,
+        fds: Vec<TypedFd>,
         ignore_monitor: bool,
     ) -> crate::Result<()> {
+        let shared_memory_threshold = *self.shared_memory_threshold.lock().unwrap();
+        let (frame, fds) = match shared_memory_threshold {
+            Some(threshold) if fds.is_empty() => self.encode_shared(message, threshold)?,
+            _ => (self.encode(message)?, fds),
+        };
+
+        if let Some(ref queue) = self.queue {
+            trace!("Queueing data: {message:?} to {}", self.peer_name);
+
+            // Outgoing calls fail if the writer task fails to write them
+            let call_id = match message.data {
+                message::RpcData::Call { .. } | message::RpcData::Subscription { .. } => {
+                    Some(message.id)
+                }
+                _ => None,
+            };
+
+            let item = QueuedWrite::Frame {
+                frame,
+                fds,
+                call_id,
+            };
+            if !queue.push(item, Priority::of(&message.data)).await {
+                return Err(crate::Error::PeerDisconnected);
+            }
+
+            // Doesn't block other writers, because the socket isn't locked
+            if !ignore_monitor {
+                Self::monitor(message, &self.peer_name).await;
+            }
+
+            return Ok(());
+        }
+
         let mut socket_lock = self.socket.lock().await;
 
-        trace!(
-            "Writing data: {message:?} into <{}>",
-            socket_lock.as_ref().as_raw_fd()
-        );
+        trace!("Writing data: {message:?} to {}", self.peer_name);
+
+        let result = Self::write_frame(&mut socket_lock, frame, fds).await;
+        if let Err(ref e) = result {
+            debug!("Failed to write a message: {e}");
+        }
+
+        if !Self::handle_write_result(&self.socket, &self.state, result) {
+            return Err(crate::Error::PeerDisconnected);
+        }
+
+        if !ignore_monitor {
+            Self::monitor(message, &self.peer_name).await;
+        }
+
+        Ok(())
+    }
+
+    /// Encode a message, which is moved into shared memory if exceeds the `threshold`.
+    /// Large messages are encoded directly into a memfd, see [shm::FrameWriter].
+    /// Returns a frame of the message, which references the memory, and the memory FD.
+    /// Falls back to sending the frame itself if fails to make the shared memory
+    fn encode_shared(
+        &self,
+        message: &RpcMessage,
+        threshold: usize,
+    ) -> crate::Result<(Vec<u8>, Vec<TypedFd>)> {
+        let mut writer = shm::FrameWriter::new(self.buffers.take(), threshold);
+
+        if let Err(e) = self.codec.encode_to(message, &mut writer) {
+            self.buffers.put(writer.into_buffer());
+
+            // Writing into the memfd may have failed. Serialization errors are returned
+            debug!("Failed to encode a message into shared memory: {e}. Encoding inline");
+            return Ok((self.encode(message)?, Vec::new()));
+        }
+
+        if let Some(e) = writer.error() {
+            warn!("Failed to place a message into shared memory: {e}. Sending inline");
+        }
+
+        let (memfd, size, buffer) = match writer.finish() {
+            Ok(shm::SharedFrame::Inline(frame)) => return Ok((frame, Vec::new())),
+            Ok(shm::SharedFrame::Shared {
+                memfd,
+                size,
+                buffer,
+            }) => (memfd, size, buffer),
+            Err(e) => {
+                warn!("Failed to place a message into shared memory: {e}. Sending inline");
+                return Ok((self.encode(message)?, Vec::new()));
+            }
+        };
+
+        trace!("Sending {size} bytes message using shared memory");
+        self.buffers.put(buffer);
+
+        let reference = RpcMessage {
+            id: message.id,
+            data: message::RpcData::SharedMemory {
+                // Frame length fits into the frame length prefix
+                size: size as u32,
+            },
+        };
+
+        Ok((self.encode(&reference)?, vec![memfd]))
+    }
+
+    /// Encode a message into a buffer from the pool
+    fn encode(&self, message: &RpcMessage) -> crate::Result<Vec<u8>> {
+        let mut frame = self.buffers.take();
+
+        match self.codec.encode_into(message, &mut frame) {
+            Ok(_) => Ok(frame),
+            Err(e) => {
+                self.buffers.put(frame);
+                Err(e)
+            }
+        }
+    }
+
+    /// Write a frame followed by its FDs.
+    /// Returns a flush delay if the frame has been batched
+    async fn write_frame(
+        socket: &mut SocketWriter,
+        frame: Vec<u8>,
+        fds: Vec<TypedFd>,
+    ) -> std::io::Result<Option<Duration>> {
+        let flush_delay = socket.write_frame(frame).await?;
+
+        if fds.is_empty() {
+            return Ok(flush_delay);
+        }
+
+        // Sending FDs flushes the batch, so there's no need to schedule a flush
+        socket.send_fds(fds).await.map(|_| None)
+    }
+
+    /// Schedule a batch flush or mark the connection disconnected on error.
+    /// Returns `true` if the write succeeded
+    fn handle_write_result(
+        socket: &Arc<Mutex<SocketWriter>>,
+        state: &Arc<watch::Sender<ConnectionState>>,
+        result: std::io::Result<Option<Duration>>,
+    ) -> bool {
+        match result {
+            Ok(Some(delay)) => {
+                Self::schedule_flush(socket.clone(), state.clone(), delay);
+                true
+            }
+            Ok(None) => true,
+            Err(_) => {
+                Self::mark_disconnected(state);
+                false
+            }
+        }
+    }
 
-        let result = socket_lock.write_message(&message).await;
+    #[allow(unused_variables)]
+    async fn monitor(message: &RpcMessage, peer_name: &str) {
+        #[cfg(feature = "monitor")]
+        {
+            use crate::monitor::{Direction, Monitor};
+            Monitor::send(message, Direction::Outgoing, peer_name).await;
+        }
+    }
+
+    /// Write pending batched messages after `delay` in background
+    fn schedule_flush(
+        socket: Arc<Mutex<SocketWriter>>,
+        state: Arc<watch::Sender<ConnectionState>>,
+        delay: Duration,
+    ) {
+        tokio::spawn(async move {
+            if delay.is_zero() {
+                // Let other tasks add their messages into the batch
+                tokio::task::yield_now().await;
+            } else {
+                tokio::time::sleep(delay).await;
+            }
+
+            if let Err(e) = socket.lock().await.flush_batch().await {
+                debug!("Failed to write batched messages: {e}");
+
+                Self::mark_disconnected(&state);
+            }
+        });
+    }
 
-        if !ignore_monitor && result.is_ok() {
-            #[cfg(feature = "monitor")]
-            {
-                use crate::monitor::{Direction, Monitor};
-                Monitor::send(message, Direction::Outgoing, &self.peer_name).await;
+    /// Write queued messages until the queue is closed.
+    /// Outgoing calls, which failed to be written, are removed from the `registry`,
+    /// which fails them with [crate::Error::PeerDisconnected]
+    async fn writer_task(
+        receiver: WriteQueueReceiver,
+        socket: Arc<Mutex<SocketWriter>>,
+        state: Arc<watch::Sender<ConnectionState>>,
+        registry: Arc<CallsRegistry>,
+    ) {
+        while let Some(item) = receiver.pop().await {
+            match item {
+                QueuedWrite::Frame {
+                    frame,
+                    fds,
+                    call_id,
+                } => {
+                    let result = Self::write_frame(&mut *socket.lock().await, frame, fds).await;
+
+                    if let Err(ref e) = result {
+                        debug!("Writer task failed to write a message: {e}");
+                    }
+
+                    if !Self::handle_write_result(&socket, &state, result) {
+                        for id in call_id.into_iter().chain(receiver.fail()) {
+                            registry.remove(id)
+                        }
+                    }
+                }
+                QueuedWrite::Flush(sender) => {
+                    let _ = socket.lock().await.flush().await;
+                    let _ = sender.send(());
+                }
             }
         }
 
-        result
+        trace!("Writer task finished");
+    }
+}
+
+/// Cancels pending call if dropped before the call is resolved
+struct CallGuard {
+    id: i64,
+    writer: Option<RpcWriter>,
+}
+
+impl CallGuard {
+    fn disarm(&mut self) {
+        self.writer = None;
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.cancel_in_background(self.id, message::RpcData::Cancel)
+        }
     }
 }
@@ -0,0 +1,94 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use futures::{select, FutureExt, StreamExt};
+use tokio::{io::AsyncReadExt, net::UnixStream};
+
+use krossbar_rpc::{
+    access::{AccessRequest, RequestKind},
+    request::Body,
+    rpc::Rpc,
+    Error,
+};
+
+const SECRET_ENDPOINT: &str = "secret";
+const PUBLIC_ENDPOINT: &str = "public";
+
+#[tokio::test]
+async fn test_access_policy() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let checks = Arc::new(AtomicUsize::new(0));
+    let policy_checks = checks.clone();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 =
+        Rpc::new(stream2, "service").with_access_policy(move |request: &AccessRequest| {
+            policy_checks.fetch_add(1, Ordering::SeqCst);
+
+            assert_eq!(request.peer_name, "service");
+            assert!(request.credentials.is_some());
+
+            match request.kind {
+                RequestKind::Connection { target_name, .. } => target_name != "forbidden",
+                _ => request.endpoint != SECRET_ENDPOINT,
+            }
+        });
+
+    rpc1.send_message(SECRET_ENDPOINT, &1).await.unwrap();
+    rpc1.send_message(PUBLIC_ENDPOINT, &2).await.unwrap();
+
+    let secret_call = rpc1.call::<u32, u32>(SECRET_ENDPOINT, &42).await.unwrap();
+    let mut secret_subscription = rpc1.subscribe::<u32>(SECRET_ENDPOINT).await.unwrap();
+
+    let (mut forbidden_peer, forbidden_stream) = UnixStream::pair().unwrap();
+    rpc1.connection_request(
+        "client",
+        "forbidden",
+        vec![forbidden_stream.try_into().unwrap()],
+    )
+    .await
+    .unwrap();
+
+    let public_call = rpc1.call::<u32, u32>(PUBLIC_ENDPOINT, &42).await.unwrap();
+
+    // Secret message is dropped
+    let mut request = rpc2.poll().await.unwrap();
+    assert_eq!(request.endpoint(), PUBLIC_ENDPOINT);
+    assert!(matches!(request.take_body(), Some(Body::Message(_))));
+
+    // Secret requests are responded automatically
+    let mut request = rpc2.poll().await.unwrap();
+    assert_eq!(request.endpoint(), PUBLIC_ENDPOINT);
+
+    let Some(Body::Call(params)) = request.take_body() else {
+        panic!("Invalid message type")
+    };
+    request.respond(Ok(params)).await;
+
+    assert_eq!(checks.load(Ordering::SeqCst), 6);
+
+    // Forbidden connection request isn't responded, and its FDs are closed
+    let mut buf = [0u8; 1];
+    assert_eq!(forbidden_peer.read(&mut buf).await.unwrap(), 0);
+
+    let responses = async {
+        assert!(matches!(secret_call.await, Err(Error::NotAllowed)));
+        assert!(matches!(
+            secret_subscription.next().await,
+            Some(Err(Error::NotAllowed))
+        ));
+        assert_eq!(public_call.await.unwrap(), 42);
+    };
+
+    select! {
+        _ = responses.fuse() => {},
+        _ = rpc1.poll().fuse() => panic!("Unexpected disconnect")
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fmt::Debug, time::Duration};
+
+use serde::de::DeserializeOwned;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+use krossbar_rpc::{request::Body, rpc::Rpc, writer::BatchOptions};
+
+const ENDPOINT_NAME: &str = "test_message";
+const NUM_MESSAGES: u32 = 100;
+
+async fn expect_message<T: DeserializeOwned + PartialEq + Debug>(rpc: &mut Rpc, expected: T) {
+    let mut request = rpc.poll().await.unwrap();
+    assert_eq!(request.endpoint(), ENDPOINT_NAME);
+
+    if let Some(Body::Message(bson)) = request.take_body() {
+        let body: T = bson::from_bson(bson).unwrap();
+        assert_eq!(body, expected);
+    } else {
+        panic!("Invalid message type")
+    }
+}
+
+async fn assert_no_message(rpc: &mut Rpc) {
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), rpc.poll())
+            .await
+            .is_err(),
+        "Unexpected incoming message"
+    );
+}
+
+#[tokio::test]
+async fn test_batched_messages() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc").with_write_batching(BatchOptions::new());
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    // Messages are written when the writing task yields
+    for i in 0..NUM_MESSAGES {
+        rpc1.send_message(ENDPOINT_NAME, &i).await.unwrap();
+    }
+
+    for i in 0..NUM_MESSAGES {
+        expect_message(&mut rpc2, i).await;
+    }
+}
+
+#[tokio::test]
+async fn test_batch_flush() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc").with_write_batching(
+        BatchOptions::new()
+            .max_size(1024)
+            .flush_delay(Duration::from_secs(3600)),
+    );
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    rpc1.send_message(ENDPOINT_NAME, &0u32).await.unwrap();
+    assert_no_message(&mut rpc2).await;
+
+    // Explicit flush writes pending messages
+    rpc1.writer().flush().await;
+    expect_message(&mut rpc2, 0).await;
+
+    // Exceeding batch size writes pending messages
+    let payload = vec![2u32; 1024];
+    rpc1.send_message(ENDPOINT_NAME, &1u32).await.unwrap();
+    rpc1.send_message(ENDPOINT_NAME, &payload).await.unwrap();
+
+    expect_message(&mut rpc2, 1u32).await;
+    expect_message(&mut rpc2, payload).await;
+}
+
+#[tokio::test]
+async fn test_batched_fd_ordering() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc").with_write_batching(
+        BatchOptions::new()
+            .max_size(1024 * 1024)
+            .flush_delay(Duration::from_secs(3600)),
+    );
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let (mut send_stream1, send_stream2) = UnixStream::pair().unwrap();
+
+    rpc1.send_message(ENDPOINT_NAME, &0u32).await.unwrap();
+    rpc1.connection_request("rpc1", "rpc2", vec![send_stream2.try_into().unwrap()])
+        .await
+        .unwrap();
+    rpc1.send_message(ENDPOINT_NAME, &1u32).await.unwrap();
+
+    // Connection request is written along with the preceding message
+    expect_message(&mut rpc2, 0).await;
+
+    let mut request = rpc2.poll().await.unwrap();
+    assert_eq!(request.endpoint(), "connect");
+
+    let Some(Body::Fd { mut fds, .. }) = request.take_body() else {
+        panic!("Invalid message type")
+    };
+    let mut stream = fds.pop().unwrap().into_unix_stream().unwrap();
+
+    send_stream1.write_all(b"ping").await.unwrap();
+    let mut buffer = [0u8; 4];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+
+    // The following message is still batched
+    assert_no_message(&mut rpc2).await;
+
+    rpc1.writer().flush().await;
+    expect_message(&mut rpc2, 1).await;
+}
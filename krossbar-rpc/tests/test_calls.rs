@@ -1,5 +1,7 @@
+use std::time::{Duration, Instant};
+
 use futures::{select, FutureExt};
-use krossbar_rpc::{request::Body, rpc::Rpc};
+use krossbar_rpc::{request::Body, rpc::Rpc, writer::CallOptions, Disconnect};
 use tokio::{io::AsyncWriteExt, net::UnixStream};
 
 const ENDPOINT_NAME: &str = "test_function";
@@ -196,3 +198,314 @@ async fn test_user_error() {
         _ = rpc1.poll().fuse() => {}
     }
 }
+
+#[tokio::test]
+async fn test_call_timeout() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let call = rpc1
+        .call_with_options::<u32, u32>(
+            ENDPOINT_NAME,
+            &42,
+            &CallOptions::new().timeout(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+
+    // Receive the request, but never respond
+    let _request = rpc2.poll().await.unwrap();
+
+    select! {
+        response = call.fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::Timeout)));
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+}
+
+#[tokio::test]
+async fn test_default_call_timeout() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc").with_call_timeout(Duration::from_millis(50));
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+    let fd_call = rpc1.call_fd::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+
+    let request = rpc2.poll().await.unwrap();
+    let fd_request = rpc2.poll().await.unwrap();
+
+    select! {
+        response = futures::future::join(call, fd_call).fuse() => {
+            assert!(matches!(response.0, Err(krossbar_rpc::Error::Timeout)));
+            assert!(matches!(response.1, Err(krossbar_rpc::Error::Timeout)));
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+
+    // Late responses are ignored
+    assert!(request.respond(Ok(420)).await);
+    assert!(fd_request.respond(Ok(420)).await);
+
+    let call = rpc1
+        .call_with_options::<u32, u32>(
+            ENDPOINT_NAME,
+            &42,
+            &CallOptions::new().timeout(Duration::from_secs(60)),
+        )
+        .await
+        .unwrap();
+
+    let request = rpc2.poll().await.unwrap();
+    assert!(request.respond(Ok(421)).await);
+
+    select! {
+        response = call.fuse() => {
+            assert_eq!(response.unwrap(), 421);
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+}
+
+#[tokio::test]
+async fn test_call_deadline() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let call = rpc1
+        .call_with_options::<u32, u32>(
+            ENDPOINT_NAME,
+            &42,
+            &CallOptions::new()
+                .timeout(Duration::from_secs(60))
+                .deadline(Instant::now() + Duration::from_millis(50)),
+        )
+        .await
+        .unwrap();
+
+    let _request = rpc2.poll().await.unwrap();
+
+    select! {
+        response = call.fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::Timeout)));
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+}
+
+#[tokio::test]
+async fn test_call_cancel() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+
+    let request = rpc2.poll().await.unwrap();
+    assert!(!request.is_cancelled());
+
+    // Drop the call to cancel it
+    drop(call);
+
+    select! {
+        _ = request.cancelled().fuse() => {
+            assert!(request.is_cancelled());
+        },
+        _ = rpc2.poll().fuse() => {
+            panic!("Should not return here")
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_call_timeout_cancel() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc").with_call_timeout(Duration::from_millis(50));
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+    let request = rpc2.poll().await.unwrap();
+
+    select! {
+        response = call.fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::Timeout)));
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+
+    select! {
+        _ = request.cancelled().fuse() => {},
+        _ = rpc2.poll().fuse() => {
+            panic!("Should not return here")
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_request_cancelled_on_disconnect() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let _call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+    let request = rpc2.poll().await.unwrap();
+
+    let (stream2, _stream3) = UnixStream::pair().unwrap();
+    rpc2.on_reconnected(Rpc::new(stream2, "rpc")).await;
+
+    assert!(request.is_cancelled());
+    request.cancelled().await;
+}
+
+#[tokio::test]
+async fn test_call_replay_reconnect() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let replay_options = CallOptions::new().replay(true);
+
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &41).await.unwrap();
+    let replay_call = rpc1
+        .call_with_options::<u32, u32>(ENDPOINT_NAME, &42, &replay_options)
+        .await
+        .unwrap();
+    let fd_call = rpc1.call_fd::<u32, u32>(ENDPOINT_NAME, &43).await.unwrap();
+
+    let first_request = rpc2.poll().await.unwrap();
+    let replay_request = rpc2.poll().await.unwrap();
+    let _ = rpc2.poll().await.unwrap();
+    drop(rpc2);
+
+    let (stream1, stream3) = UnixStream::pair().unwrap();
+    rpc1.on_reconnected(Rpc::new(stream1, "rpc")).await;
+    let mut rpc3 = Rpc::new(stream3, "rpc");
+
+    // Non-replayed calls fail
+    assert!(matches!(
+        call.await,
+        Err(krossbar_rpc::Error::PeerDisconnected)
+    ));
+    assert!(matches!(
+        fd_call.await,
+        Err(krossbar_rpc::Error::PeerDisconnected)
+    ));
+
+    // Replayed call is resent using the same id
+    let mut request = rpc3.poll().await.unwrap();
+    assert_ne!(request.message_id(), first_request.message_id());
+    assert_eq!(request.message_id(), replay_request.message_id());
+    assert_eq!(request.endpoint(), ENDPOINT_NAME);
+
+    if let Some(Body::Call(bson)) = request.take_body() {
+        let request_body: u32 = bson::from_bson(bson).unwrap();
+        assert_eq!(request_body, 42);
+    } else {
+        panic!("Invalid message type")
+    }
+
+    assert!(request.respond(Ok(420)).await);
+
+    select! {
+        response = replay_call.fuse() => {
+            assert_eq!(response.unwrap(), 420);
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+
+    // Resolved calls are not replayed on the next reconnect
+    let (stream1, stream4) = UnixStream::pair().unwrap();
+    rpc1.on_reconnected(Rpc::new(stream1, "rpc")).await;
+    drop(rpc1);
+
+    let mut rpc4 = Rpc::new(stream4, "rpc");
+    assert!(matches!(rpc4.poll().await, Err(Disconnect::Closed)));
+}
+
+#[tokio::test]
+async fn test_fd_call_replay_reconnect() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let fd_call = rpc1
+        .call_fd_with_options::<u32, u32>(ENDPOINT_NAME, &42, &CallOptions::new().replay(true))
+        .await
+        .unwrap();
+
+    let _ = rpc2.poll().await.unwrap();
+    drop(rpc2);
+
+    let (stream1, stream3) = UnixStream::pair().unwrap();
+    rpc1.on_reconnected(Rpc::new(stream1, "rpc")).await;
+    let mut rpc3 = Rpc::new(stream3, "rpc");
+
+    let request = rpc3.poll().await.unwrap();
+    let (send_stream, _) = UnixStream::pair().unwrap();
+    assert!(
+        request
+            .respond_with_fd(Ok(420), vec![send_stream.try_into().unwrap()])
+            .await
+    );
+
+    select! {
+        response = fd_call.fuse() => {
+            assert_eq!(response.unwrap().0, 420);
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+}
+
+#[tokio::test]
+async fn test_pending_calls_rpc_dropped() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let writer = rpc1.writer().clone();
+
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+    let replay_call = rpc1
+        .call_with_options::<u32, u32>(ENDPOINT_NAME, &42, &CallOptions::new().replay(true))
+        .await
+        .unwrap();
+
+    // Peer disconnects without responding
+    drop(stream2);
+    assert!(rpc1.poll().await.is_err());
+
+    // Calls, which are not replayed, fail once the connection is lost
+    assert!(matches!(
+        call.await,
+        Err(krossbar_rpc::Error::PeerDisconnected)
+    ));
+
+    // Replayed calls fail once the handle is dropped, because no one is going to reconnect
+    drop(rpc1);
+    assert!(matches!(
+        replay_call.await,
+        Err(krossbar_rpc::Error::PeerDisconnected)
+    ));
+
+    // Writer handles outlive the connection. New calls fail
+    if let Ok(call) = writer.call::<u32, u32>(ENDPOINT_NAME, &42).await {
+        assert!(matches!(
+            call.await,
+            Err(krossbar_rpc::Error::PeerDisconnected)
+        ));
+    }
+}
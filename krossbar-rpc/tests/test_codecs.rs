@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use futures::{select, FutureExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixStream;
+
+use krossbar_rpc::{codec::Codec, request::Body, rpc::Rpc, Disconnect};
+
+const CODECS: [Codec; 3] = [Codec::Bson, Codec::MessagePack, Codec::Cbor];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Payload {
+    id: i64,
+    name: String,
+    ratio: f64,
+    enabled: bool,
+    tags: Vec<String>,
+    parent: Option<Box<Payload>>,
+    values: HashMap<String, i32>,
+}
+
+fn payload() -> Payload {
+    Payload {
+        id: -42,
+        name: "test".into(),
+        ratio: 0.5,
+        enabled: true,
+        tags: vec!["one".into(), "two".into()],
+        parent: Some(Box::new(Payload {
+            id: i64::MAX,
+            name: "parent".into(),
+            ratio: -1e10,
+            enabled: false,
+            tags: vec![],
+            parent: None,
+            values: HashMap::new(),
+        })),
+        values: HashMap::from([("a".into(), 1), ("b".into(), i32::MIN)]),
+    }
+}
+
+/// Echo calls and stream 3 copies of a subscription endpoint name
+async fn serve(mut rpc: Rpc) {
+    while let Ok(mut request) = rpc.poll().await {
+        match request.take_body() {
+            Some(Body::Call(params)) => {
+                request.respond(Ok(params)).await;
+            }
+            Some(Body::Subscription) => {
+                let endpoint = request.endpoint().to_owned();
+                let sink = request.into_subscription();
+
+                for _ in 0..3 {
+                    sink.send(&endpoint).await;
+                }
+                sink.close().await;
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn make_pair(codec: Codec) -> Rpc {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    tokio::spawn(serve(Rpc::new(stream2, "rpc").with_codec(codec)));
+    Rpc::new(stream1, "rpc").with_codec(codec)
+}
+
+#[tokio::test]
+async fn test_codecs_interop() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    for codec in CODECS {
+        let mut rpc = make_pair(codec).await;
+
+        let struct_call = rpc.call::<_, Payload>("echo", &payload()).await.unwrap();
+        let number_call = rpc.call::<_, u32>("echo", &42u32).await.unwrap();
+        let string_call = rpc.call::<_, String>("echo", &"hello").await.unwrap();
+        let array_call = rpc
+            .call::<_, Vec<i64>>("echo", &vec![1, -2, 3])
+            .await
+            .unwrap();
+        let unit_call = rpc.call::<_, ()>("echo", &()).await.unwrap();
+        let subscription = rpc.subscribe::<String>("signal").await.unwrap();
+
+        let calls = async {
+            assert_eq!(struct_call.await.unwrap(), payload(), "{codec}");
+            assert_eq!(number_call.await.unwrap(), 42, "{codec}");
+            assert_eq!(string_call.await.unwrap(), "hello", "{codec}");
+            assert_eq!(array_call.await.unwrap(), vec![1, -2, 3], "{codec}");
+            unit_call.await.unwrap();
+
+            let items: Vec<String> = subscription.map(|item| item.unwrap()).collect().await;
+            assert_eq!(items, vec!["signal"; 3], "{codec}");
+        };
+
+        select! {
+            _ = calls.fuse() => {},
+            _ = rpc.poll().fuse() => panic!("Unexpected disconnect")
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_codec_mismatch() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    for sender_codec in CODECS {
+        for receiver_codec in CODECS {
+            if sender_codec == receiver_codec {
+                continue;
+            }
+
+            let (stream1, stream2) = UnixStream::pair().unwrap();
+
+            let rpc1 = Rpc::new(stream1, "rpc").with_codec(sender_codec);
+            let mut rpc2 = Rpc::new(stream2, "rpc").with_codec(receiver_codec);
+
+            rpc1.send_message("message", &42).await.unwrap();
+
+            let reason = rpc2.poll().await.unwrap_err();
+            assert!(reason.is_protocol_violation());
+
+            if let Disconnect::CodecMismatch { expected, received } = reason {
+                assert_eq!(expected, receiver_codec);
+                assert_eq!(received, sender_codec);
+            } else {
+                panic!("Unexpected disconnect reason: {reason:?}")
+            }
+        }
+    }
+}
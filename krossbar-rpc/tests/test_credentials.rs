@@ -0,0 +1,82 @@
+use std::os::unix::fs::MetadataExt;
+
+use tokio::net::UnixStream;
+
+use krossbar_rpc::{request::Body, rpc::Rpc, transport::PeerCredentials};
+
+/// Credentials of the current process
+fn own_credentials() -> PeerCredentials {
+    let metadata = std::fs::metadata("/proc/self").unwrap();
+
+    PeerCredentials {
+        pid: Some(std::process::id() as i32),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    }
+}
+
+#[tokio::test]
+async fn test_peer_credentials() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    assert_eq!(rpc1.peer_credentials(), Some(own_credentials()));
+    assert_eq!(rpc2.writer().peer_credentials(), Some(own_credentials()));
+
+    let _call = rpc1.call::<u32, u32>("test_function", &42).await.unwrap();
+
+    let request = rpc2.poll().await.unwrap();
+    assert_eq!(request.peer_credentials(), Some(own_credentials()));
+}
+
+#[tokio::test]
+async fn test_fd_credentials() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let (_send_stream1, send_stream2) = UnixStream::pair().unwrap();
+    rpc1.connection_request("rpc1", "rpc2", vec![send_stream2.try_into().unwrap()])
+        .await
+        .unwrap();
+
+    let mut request = rpc2.poll().await.unwrap();
+
+    let Some(Body::Fd {
+        credentials,
+        mut fds,
+        ..
+    }) = request.take_body()
+    else {
+        panic!("Invalid message type")
+    };
+
+    assert_eq!(credentials, Some(own_credentials()));
+    assert_eq!(fds[0].peer_credentials(), credentials);
+
+    let stream = fds.pop().unwrap().into_unix_stream().unwrap();
+    assert_eq!(PeerCredentials::from_stream(&stream), credentials);
+}
+
+#[tokio::test]
+async fn test_no_credentials() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, _stream2) = tokio::io::duplex(1024);
+
+    let rpc = Rpc::new(stream1, "rpc");
+    assert_eq!(rpc.peer_credentials(), None);
+}
@@ -1,5 +1,11 @@
+use std::{fs::File, io::Write, os::unix::fs::FileExt};
+
 use futures::{select, FutureExt};
-use krossbar_rpc::{request::Body, rpc::Rpc};
+use krossbar_rpc::{
+    fd::{FdKind, TypedFd},
+    request::Body,
+    rpc::Rpc,
+};
 use tokio::net::UnixStream;
 
 const CLIENT_NAME: &str = "com.test.client";
@@ -42,7 +48,7 @@ async fn test_fd_send() {
 
     let (send_stream1, send_stream2) = UnixStream::pair().unwrap();
 
-    rpc1.connection_request("rpc1", CLIENT_NAME, send_stream2)
+    rpc1.connection_request("rpc1", CLIENT_NAME, vec![send_stream2.try_into().unwrap()])
         .await
         .unwrap();
 
@@ -52,11 +58,14 @@ async fn test_fd_send() {
 
     let received_rpc = if let Some(Body::Fd {
         target_name,
-        stream,
+        mut fds,
         ..
     }) = request.take_body()
     {
         assert_eq!(target_name, CLIENT_NAME);
+        assert_eq!(fds.len(), 1);
+
+        let stream = fds.pop().unwrap().into_unix_stream().unwrap();
         Rpc::new(stream, "rpc")
     } else {
         panic!("Invalid message type")
@@ -130,22 +139,122 @@ async fn test_fd_response() {
     }
 
     let (send_stream1, send_stream2) = UnixStream::pair().unwrap();
-    assert!(request.respond_with_fd(Ok(420), send_stream2).await);
+    assert!(
+        request
+            .respond_with_fd(Ok(420), vec![send_stream2.try_into().unwrap()])
+            .await
+    );
 
     let received_stream = select! {
         response = call.fuse() => {
-            let (data, stream) = response.unwrap();
+            let (data, mut fds) = response.unwrap();
+            assert_eq!(data, 420);
+            assert_eq!(fds.len(), 1);
+
+            fds.pop().unwrap().into_unix_stream().unwrap()
+        },
+        _ = rpc1.poll().fuse() => {
+            panic!("Should not return here")
+        }
+    };
+
+    test_pair_call(
+        Rpc::new(received_stream, "rpc"),
+        Rpc::new(send_stream1, "rpc"),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_multiple_fds_response() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let call = rpc1.call_fd::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+
+    // Poll the stream to receive the request
+    let request = rpc2.poll().await.unwrap();
+    assert_eq!(request.endpoint(), ENDPOINT_NAME);
+
+    let path = std::env::temp_dir().join(format!("krossbar-fd-test-{}", std::process::id()));
+    let mut file = File::options()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file.write_all(b"shared data").unwrap();
+
+    let (send_stream1, send_stream2) = UnixStream::pair().unwrap();
+    let fds = vec![
+        TypedFd::from(file),
+        send_stream2.try_into().unwrap(),
+        TypedFd::new(FdKind::Other, File::open("/dev/null").unwrap()),
+    ];
+    assert!(request.respond_with_fd(Ok(420), fds).await);
+
+    let mut received_fds = select! {
+        response = call.fuse() => {
+            let (data, fds) = response.unwrap();
             assert_eq!(data, 420);
-            stream
+            fds
         },
         _ = rpc1.poll().fuse() => {
             panic!("Should not return here")
         }
     };
 
+    let kinds: Vec<FdKind> = received_fds.iter().map(TypedFd::kind).collect();
+    assert_eq!(kinds, [FdKind::File, FdKind::UnixStream, FdKind::Other]);
+
+    // Only streams can be converted into a stream
+    assert!(received_fds.pop().unwrap().into_unix_stream().is_err());
+
+    let received_stream = received_fds.pop().unwrap().into_unix_stream().unwrap();
+
+    let received_file = File::from(received_fds.pop().unwrap().into_fd());
+    let mut buffer = [0u8; 11];
+    received_file.read_exact_at(&mut buffer, 0).unwrap();
+    assert_eq!(&buffer, b"shared data");
+
     test_pair_call(
         Rpc::new(received_stream, "rpc"),
         Rpc::new(send_stream1, "rpc"),
     )
     .await
 }
+
+#[tokio::test]
+async fn test_sent_fd_closed() {
+    fn open_fds() -> usize {
+        std::fs::read_dir("/proc/self/fd").unwrap().count()
+    }
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let fds_before = open_fds();
+
+    for _ in 0..100 {
+        let (send_stream, _) = UnixStream::pair().unwrap();
+        rpc1.connection_request("rpc1", CLIENT_NAME, vec![send_stream.try_into().unwrap()])
+            .await
+            .unwrap();
+
+        // Received stream is dropped
+        let _ = rpc2.poll().await.unwrap();
+    }
+
+    // Other tests may open descriptors concurrently
+    assert!(open_fds() < fds_before + 50);
+}
@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use futures::{select, FutureExt};
+use tokio::{io::AsyncWriteExt, net::UnixStream};
+
+use krossbar_rpc::{
+    bson::doc,
+    codec::Codec,
+    handshake::{Features, HandshakeOptions, PROTOCOL_VERSION},
+    request::Body,
+    rpc::Rpc,
+    HandshakeError,
+};
+
+async fn handshake_pair(
+    options1: HandshakeOptions,
+    options2: HandshakeOptions,
+) -> (Result<Rpc, HandshakeError>, Result<Rpc, HandshakeError>) {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let peer = tokio::spawn(Rpc::handshake(stream2, options2));
+    let result = Rpc::handshake(stream1, options1).await;
+
+    (result, peer.await.unwrap())
+}
+
+#[tokio::test]
+async fn test_handshake() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (rpc1, rpc2) = handshake_pair(
+        HandshakeOptions::new("client").codec(Codec::MessagePack),
+        HandshakeOptions::new("service")
+            .codec(Codec::MessagePack)
+            .require(Features {
+                fd_passing: true,
+                ..Default::default()
+            }),
+    )
+    .await;
+
+    let mut rpc1 = rpc1.unwrap();
+    let mut rpc2 = rpc2.unwrap();
+
+    assert_eq!(rpc1.peer_name(), "service");
+    assert_eq!(rpc2.peer_name(), "client");
+
+    let peer_info = rpc1.peer_info().unwrap();
+    assert_eq!(peer_info.protocol_version, PROTOCOL_VERSION);
+    assert!(peer_info.features.fd_passing);
+    assert!(peer_info.features.cancellation);
+    assert!(!peer_info.features.compression);
+    assert!(peer_info.features.shared_memory);
+
+    // Messages are exchanged using negotiated codec
+    let call = rpc1.call::<u32, u32>("echo", &42).await.unwrap();
+
+    let mut request = rpc2.poll().await.unwrap();
+    assert_eq!(request.endpoint(), "echo");
+
+    let Some(Body::Call(params)) = request.take_body() else {
+        panic!("Invalid message type")
+    };
+    request.respond(Ok(params)).await;
+
+    select! {
+        response = call.fuse() => assert_eq!(response.unwrap(), 42),
+        _ = rpc1.poll().fuse() => panic!("Unexpected disconnect")
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_codec_mismatch() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (rpc1, rpc2) = handshake_pair(
+        HandshakeOptions::new("client").codec(Codec::Cbor),
+        HandshakeOptions::new("service"),
+    )
+    .await;
+
+    assert!(matches!(
+        rpc1,
+        Err(HandshakeError::CodecMismatch {
+            local: Codec::Cbor,
+            peer: Codec::Bson
+        })
+    ));
+    assert!(matches!(
+        rpc2,
+        Err(HandshakeError::CodecMismatch {
+            local: Codec::Bson,
+            peer: Codec::Cbor
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_handshake_missing_features() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (rpc1, rpc2) = handshake_pair(
+        HandshakeOptions::new("client").require(Features {
+            compression: true,
+            ..Default::default()
+        }),
+        HandshakeOptions::new("service"),
+    )
+    .await;
+
+    match rpc1 {
+        Err(HandshakeError::MissingFeatures(features)) => assert_eq!(
+            features,
+            Features {
+                compression: true,
+                ..Default::default()
+            }
+        ),
+        other => panic!("Unexpected handshake result: {:?}", other.err()),
+    }
+
+    // Peer doesn't require compression
+    assert!(rpc2.is_ok());
+}
+
+#[tokio::test]
+async fn test_handshake_incompatible_version() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, mut stream2) = UnixStream::pair().unwrap();
+
+    // Hello from a future incompatible version
+    let hello = doc! {
+        "hello": "krossbar",
+        "version": 100,
+        "min_version": 100,
+        "codec": "Bson",
+        "features": { "fd_passing": true, "future_feature": true },
+        "name": "future",
+    };
+    stream2
+        .write_all(&krossbar_rpc::bson::to_vec(&hello).unwrap())
+        .await
+        .unwrap();
+
+    match Rpc::handshake(stream1, HandshakeOptions::new("client")).await {
+        Err(HandshakeError::IncompatibleVersion { local, peer }) => {
+            assert_eq!(local, PROTOCOL_VERSION);
+            assert_eq!(peer, 100);
+        }
+        other => panic!("Unexpected handshake result: {:?}", other.err()),
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_not_supported() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    // Peer, which doesn't support handshake, sends a message
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc = Rpc::new(stream2, "legacy");
+    rpc.send_message("message", &42).await.unwrap();
+
+    assert!(matches!(
+        Rpc::handshake(stream1, HandshakeOptions::new("client")).await,
+        Err(HandshakeError::NotSupported)
+    ));
+
+    // Peer, which doesn't support handshake, waits for a request
+    let (stream1, _stream2) = UnixStream::pair().unwrap();
+
+    let options = HandshakeOptions::new("client").timeout(Duration::from_millis(100));
+    assert!(matches!(
+        Rpc::handshake(stream1, options).await,
+        Err(HandshakeError::Timeout)
+    ));
+}
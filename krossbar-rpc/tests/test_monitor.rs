@@ -4,7 +4,9 @@ use futures::{lock::Mutex, select, FutureExt, StreamExt};
 use once_cell::sync::Lazy;
 use tokio::net::UnixStream;
 
-use krossbar_rpc::{monitor::Monitor, request::Body, rpc::Rpc, Direction, MonitorMessage, RpcData};
+use krossbar_rpc::{
+    fd::FdKind, monitor::Monitor, request::Body, rpc::Rpc, Direction, MonitorMessage, RpcData,
+};
 
 const CLIENT_NAME: &str = "com.test.client";
 const ENDPOINT_NAME: &str = "test_function";
@@ -40,7 +42,7 @@ async fn test_monitor_fd_send() {
 
     let (_send_stream1, send_stream2) = UnixStream::pair().unwrap();
 
-    rpc1.connection_request("rpc1", CLIENT_NAME, send_stream2)
+    rpc1.connection_request("rpc1", CLIENT_NAME, vec![send_stream2.try_into().unwrap()])
         .await
         .unwrap();
 
@@ -55,7 +57,7 @@ async fn test_monitor_fd_send() {
     assert!(matches!(sent_message.direction, Direction::Outgoing));
     assert!(matches!(
         sent_message.message.data,
-        RpcData::ConnectionRequest { .. }
+        RpcData::ConnectionRequest { ref fds, .. } if fds[..] == [FdKind::UnixStream]
     ));
 
     let received_message = next_monitor_message(&mut monitor_receiver).await;
@@ -63,7 +65,7 @@ async fn test_monitor_fd_send() {
     assert!(matches!(received_message.direction, Direction::Incoming));
     assert!(matches!(
         received_message.message.data,
-        RpcData::ConnectionRequest { .. }
+        RpcData::ConnectionRequest { ref fds, .. } if fds[..] == [FdKind::UnixStream]
     ));
 }
 
@@ -91,7 +93,11 @@ async fn test_monitor_fd_response() {
 
     // Respond
     let (_send_stream1, send_stream2) = UnixStream::pair().unwrap();
-    assert!(request.respond_with_fd(Ok(420), send_stream2).await);
+    assert!(
+        request
+            .respond_with_fd(Ok(420), vec![send_stream2.try_into().unwrap()])
+            .await
+    );
 
     // Poll the stream to receive the request
     let _ = select! {
@@ -128,7 +134,7 @@ async fn test_monitor_fd_response() {
     assert!(matches!(sent_fd_message.direction, Direction::Outgoing));
     assert!(matches!(
         sent_fd_message.message.data,
-        RpcData::FdResponse(_)
+        RpcData::FdResponse { result: Ok(_), ref fds } if fds[..] == [FdKind::UnixStream]
     ));
 
     // FD response received
@@ -137,7 +143,7 @@ async fn test_monitor_fd_response() {
     assert!(matches!(received_fd_message.direction, Direction::Incoming));
     assert!(matches!(
         received_fd_message.message.data,
-        RpcData::FdResponse(_)
+        RpcData::FdResponse { result: Ok(_), ref fds } if fds[..] == [FdKind::UnixStream]
     ));
 }
 
@@ -0,0 +1,171 @@
+use futures::{select, FutureExt};
+use krossbar_rpc::{fd::FdKind, request::Body, rpc::Rpc, Disconnect, RpcData, RpcMessage};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+const ENDPOINT_NAME: &str = "test_function";
+
+/// Write raw `data` into the RPC connection and check that the RPC closes the connection
+async fn test_hostile_frame(data: &[u8]) {
+    let (stream1, mut stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc = Rpc::new(stream1, "rpc");
+
+    stream2.write_all(data).await.unwrap();
+
+    assert!(matches!(
+        rpc.poll().await,
+        Err(Disconnect::MalformedFrame(_))
+    ));
+    // Subsequent polls return immediately
+    assert!(matches!(rpc.poll().await, Err(Disconnect::Closed)));
+
+    // Peer receives EOF
+    let mut buffer = Vec::new();
+    assert_eq!(stream2.read_to_end(&mut buffer).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_negative_length() {
+    test_hostile_frame(&(-1i32).to_le_bytes()).await
+}
+
+#[tokio::test]
+async fn test_short_length() {
+    test_hostile_frame(&4i32.to_le_bytes()).await
+}
+
+#[tokio::test]
+async fn test_huge_length() {
+    test_hostile_frame(&i32::MAX.to_le_bytes()).await
+}
+
+#[tokio::test]
+async fn test_truncated_frame() {
+    let (stream1, mut stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc = Rpc::new(stream1, "rpc");
+
+    stream2.write_all(&100i32.to_le_bytes()).await.unwrap();
+    stream2.write_all(&[1u8; 10]).await.unwrap();
+    stream2.shutdown().await.unwrap();
+
+    assert!(matches!(
+        rpc.poll().await,
+        Err(Disconnect::MalformedFrame(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_clean_disconnect() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc = Rpc::new(stream1, "rpc");
+    drop(stream2);
+
+    assert!(matches!(rpc.poll().await, Err(Disconnect::Closed)));
+}
+
+#[tokio::test]
+async fn test_malformed_bson() {
+    let mut data = 12i32.to_le_bytes().to_vec();
+    // Invalid element type
+    data.extend_from_slice(&[0x42, b'a', 0, 1, 2, 3, 4, 0]);
+
+    test_hostile_frame(&data).await
+}
+
+#[tokio::test]
+async fn test_unknown_message() {
+    let (stream1, mut stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc = Rpc::new(stream1, "rpc");
+
+    let mut data = Vec::new();
+    bson::doc! { "id": 1i64, "data": "UnknownVariant" }
+        .to_writer(&mut data)
+        .unwrap();
+    stream2.write_all(&data).await.unwrap();
+
+    let result = rpc.poll().await;
+    assert!(matches!(result, Err(Disconnect::UnknownMessage(_))));
+    assert!(result.unwrap_err().is_protocol_violation());
+
+    let mut buffer = Vec::new();
+    assert_eq!(stream2.read_to_end(&mut buffer).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_max_message_size() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc").with_max_message_size(128);
+
+    // Fits into the limit
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+    let request = rpc2.poll().await.unwrap();
+    assert!(request.respond(Ok(420)).await);
+
+    select! {
+        response = call.fuse() => {
+            assert_eq!(response.unwrap(), 420);
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+
+    // Exceeds the limit
+    let _call = rpc1
+        .call::<String, u32>(ENDPOINT_NAME, &"x".repeat(256))
+        .await
+        .unwrap();
+    assert!(matches!(
+        rpc2.poll().await,
+        Err(Disconnect::MalformedFrame(_))
+    ));
+
+    // Connection is closed by the peer
+    assert!(matches!(rpc1.poll().await, Err(Disconnect::Closed)));
+}
+
+#[tokio::test]
+async fn test_varying_message_sizes() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    // Messages of varying sizes are read using the same buffer. See `message_stream` tests
+    // for the buffer capacity checks
+    for size in [16, 1024, 256 * 1024, 8, 128 * 1024, 0] {
+        let message = "x".repeat(size);
+        // Large messages don't fit into the socket buffer. Read while writing
+        let (sent, request) =
+            futures::join!(rpc1.send_message(ENDPOINT_NAME, &message), rpc2.poll());
+        sent.unwrap();
+
+        let mut request = request.unwrap();
+        match request.take_body() {
+            Some(Body::Message(body)) => {
+                assert_eq!(bson::from_bson::<String>(body).unwrap(), message)
+            }
+            _ => panic!("Invalid message type"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_too_many_fds() {
+    let message = RpcMessage {
+        id: 0,
+        data: RpcData::ConnectionRequest {
+            client_name: "client".to_owned(),
+            target_name: "service".to_owned(),
+            fds: vec![FdKind::UnixStream; 254],
+        },
+    };
+
+    test_hostile_frame(&bson::to_vec(&message).unwrap()).await;
+}
@@ -0,0 +1,268 @@
+use std::{path::PathBuf, time::Duration};
+
+use futures::{pin_mut, select, stream::BoxStream, FutureExt, StreamExt};
+use krossbar_rpc::{
+    handshake::HandshakeOptions,
+    reconnect::{Backoff, ReconnectingRpc},
+    rpc::Rpc,
+    writer::ConnectionState,
+    Disconnect,
+};
+use tokio::net::{UnixListener, UnixStream};
+
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("krossbar-{name}-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn backoff() -> Backoff {
+    Backoff::new()
+        .initial_delay(Duration::from_millis(10))
+        .max_delay(Duration::from_millis(50))
+}
+
+fn state_changes(rpc: &Rpc) -> BoxStream<'static, ConnectionState> {
+    futures::stream::unfold(rpc.watch_connection_state(), |mut state| async move {
+        state.changed().await.ok()?;
+        let value = *state.borrow_and_update();
+
+        Some((value, state))
+    })
+    .boxed()
+}
+
+#[tokio::test]
+async fn test_reconnect() {
+    let path = socket_path("reconnect");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let mut rpc = ReconnectingRpc::connect(&path, "service", backoff())
+        .await
+        .unwrap();
+    assert_eq!(rpc.connection_state(), ConnectionState::Connected);
+
+    let mut states = state_changes(&rpc).fuse();
+    let writer = rpc.writer().clone();
+
+    // Drop the first connection, then serve a call from the second one
+    let (stream, _) = listener.accept().await.unwrap();
+    drop(stream);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut service_rpc = Rpc::new(stream, "client");
+
+        loop {
+            let request = service_rpc.poll().await.unwrap();
+            request.respond(Ok(42u32)).await;
+        }
+    });
+
+    {
+        let poll = rpc.poll().fuse();
+        pin_mut!(poll);
+
+        let mut changes = Vec::new();
+        while changes.last() != Some(&ConnectionState::Reconnected { generation: 1 }) {
+            select! {
+                _ = poll => panic!("Should not return here"),
+                state = states.next() => changes.push(state.unwrap()),
+            }
+        }
+
+        assert_eq!(
+            changes,
+            vec![
+                ConnectionState::Reconnecting { attempt: 1 },
+                ConnectionState::Reconnected { generation: 1 }
+            ]
+        );
+    }
+
+    // Old writer handles use the new connection
+    let call = writer.call::<(), u32>("echo", &()).await.unwrap();
+    select! {
+        response = call.fuse() => assert_eq!(response.unwrap(), 42),
+        _ = rpc.poll().fuse() => panic!("Should not return here"),
+    }
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_reconnect_attempts_exhausted() {
+    let path = socket_path("reconnect-exhausted");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let mut rpc = ReconnectingRpc::connect(&path, "service", backoff().max_attempts(3))
+        .await
+        .unwrap();
+    let mut state = rpc.watch_connection_state();
+    let states = tokio::spawn(async move {
+        let mut changes = Vec::new();
+        while changes.last() != Some(&ConnectionState::Disconnected) {
+            state.changed().await.unwrap();
+            changes.push(*state.borrow_and_update());
+        }
+
+        changes
+    });
+
+    drop(listener);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(rpc.poll().await.is_err());
+    assert_eq!(rpc.connection_state(), ConnectionState::Disconnected);
+
+    // Subsequent polls don't try to reconnect
+    assert!(rpc.poll().await.is_err());
+
+    assert_eq!(
+        states.await.unwrap(),
+        vec![
+            ConnectionState::Reconnecting { attempt: 1 },
+            ConnectionState::Reconnecting { attempt: 2 },
+            ConnectionState::Reconnecting { attempt: 3 },
+            ConnectionState::Disconnected
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_initial_connection_retry() {
+    let path = socket_path("initial");
+
+    let rpc = ReconnectingRpc::connect(&path, "service", backoff().max_attempts(2)).await;
+    assert!(rpc.is_err());
+
+    // Drop the first connection before the handshake, so the connection has to be retried
+    let listener = UnixListener::bind(&path).unwrap();
+    let service = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        drop(stream);
+
+        let (stream, _) = listener.accept().await.unwrap();
+        Rpc::handshake(stream, HandshakeOptions::new("service"))
+            .await
+            .unwrap()
+    });
+
+    let rpc = ReconnectingRpc::handshake(&path, HandshakeOptions::new("client"), backoff())
+        .await
+        .unwrap();
+    assert_eq!(rpc.connection_state(), ConnectionState::Connected);
+    assert_eq!(rpc.peer_name(), "service");
+
+    let _service_rpc = service.await.unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_connection_state() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let writer = rpc1.writer().clone();
+    let mut state = writer.watch_connection_state();
+
+    assert_eq!(writer.connection_state(), ConnectionState::Connected);
+
+    drop(stream2);
+    assert!(matches!(rpc1.poll().await, Err(Disconnect::Closed)));
+
+    state.changed().await.unwrap();
+    assert_eq!(*state.borrow_and_update(), ConnectionState::Disconnected);
+    assert_eq!(writer.connection_state(), ConnectionState::Disconnected);
+
+    for generation in 1..=2 {
+        let (stream1, _stream2) = UnixStream::pair().unwrap();
+        rpc1.on_reconnected(Rpc::new(stream1, "rpc")).await;
+
+        state.changed().await.unwrap();
+        assert_eq!(
+            *state.borrow_and_update(),
+            ConnectionState::Reconnected { generation }
+        );
+        assert_eq!(
+            rpc1.connection_state(),
+            ConnectionState::Reconnected { generation }
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_connection_state_write_failure() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc");
+    drop(stream2);
+
+    // Writer detects disconnection without polling
+    let mut state = rpc1.watch_connection_state();
+    while rpc1.send_message("message", &42).await.is_ok() {}
+
+    state.changed().await.unwrap();
+    assert_eq!(*state.borrow(), ConnectionState::Disconnected);
+}
+
+#[tokio::test]
+async fn test_reconnect_handshake() {
+    let path = socket_path("reconnect-handshake");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    // Every connection starts with a handshake. Drop the first one, then serve a call from
+    // the second one
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service_rpc = Rpc::handshake(stream, HandshakeOptions::new("service"))
+            .await
+            .unwrap();
+        drop(service_rpc);
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut service_rpc = Rpc::handshake(stream, HandshakeOptions::new("new_service"))
+            .await
+            .unwrap();
+
+        loop {
+            let request = service_rpc.poll().await.unwrap();
+            request.respond(Ok(42u32)).await;
+        }
+    });
+
+    let mut rpc = ReconnectingRpc::handshake(&path, HandshakeOptions::new("client"), backoff())
+        .await
+        .unwrap();
+    assert_eq!(rpc.rpc().peer_name(), "service");
+    assert!(rpc.rpc().peer_info().is_some());
+
+    let mut states = state_changes(&rpc).fuse();
+    let writer = rpc.writer().clone();
+
+    {
+        let poll = rpc.poll().fuse();
+        pin_mut!(poll);
+
+        loop {
+            select! {
+                _ = poll => panic!("Should not return here"),
+                state = states.next() => if state == Some(ConnectionState::Reconnected { generation: 1 }) {
+                    break;
+                },
+            }
+        }
+    }
+
+    // Peer info is taken from the new handshake
+    assert_eq!(rpc.rpc().peer_name(), "new_service");
+    assert!(rpc.rpc().peer_info().is_some());
+
+    let call = writer.call::<(), u32>("echo", &()).await.unwrap();
+    select! {
+        response = call.fuse() => assert_eq!(response.unwrap(), 42),
+        _ = rpc.poll().fuse() => panic!("Should not return here"),
+    }
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,376 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{select, FutureExt, StreamExt};
+use krossbar_rpc::{fd::FdKind, request::Body, router::Router, rpc::Rpc, Disconnect};
+use tokio::{io::AsyncReadExt, net::UnixStream, sync::mpsc};
+
+const CLIENT_NAME: &str = "com.test.client";
+
+fn make_router(messages: mpsc::UnboundedSender<String>) -> Router {
+    Router::new()
+        .on_call("echo", |value: u32| async move { Ok(value) })
+        .on_call("fail", |_: ()| async move {
+            Err::<u32, _>(krossbar_rpc::Error::ClientError("Test error".to_owned()))
+        })
+        .on_message("log", move |message: String| {
+            let messages = messages.clone();
+            async move { messages.send(message).unwrap() }
+        })
+        .on_subscription("counter", |sink| async move {
+            for i in 0u32..3 {
+                sink.send(i).await;
+            }
+
+            sink.close().await;
+        })
+}
+
+#[tokio::test]
+async fn test_router_calls() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+
+    let (sender, _) = mpsc::unbounded_channel();
+    let router = make_router(sender);
+    tokio::spawn(async move { router.run(&mut rpc2).await });
+
+    let call = rpc1.call::<u32, u32>("echo", &42).await.unwrap();
+    select! {
+        response = call.fuse() => assert_eq!(response.unwrap(), 42),
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    let call = rpc1.call::<(), u32>("fail", &()).await.unwrap();
+    select! {
+        response = call.fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::ClientError(_))))
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    let call = rpc1
+        .call::<String, u32>("echo", &"42".to_owned())
+        .await
+        .unwrap();
+    select! {
+        response = call.fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::ParamsTypeError(_))))
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    let call = rpc1.call::<u32, u32>("unknown", &42).await.unwrap();
+    select! {
+        response = call.fuse() => {
+            assert!(matches!(response, Err(krossbar_rpc::Error::NoEndpoint)))
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
+
+#[tokio::test]
+async fn test_router_messages() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let router = make_router(sender);
+    let handle = tokio::spawn(async move { router.run(&mut rpc2).await });
+
+    // Unknown message and invalid body are ignored
+    rpc1.send_message("unknown", &"Hello").await.unwrap();
+    rpc1.send_message("log", &42).await.unwrap();
+    rpc1.send_message("log", &"Hello").await.unwrap();
+
+    assert_eq!(receiver.recv().await.unwrap(), "Hello");
+
+    drop(rpc1);
+    assert!(matches!(handle.await.unwrap(), Disconnect::Closed));
+}
+
+#[tokio::test]
+async fn test_router_subscriptions() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+
+    let (sender, _) = mpsc::unbounded_channel();
+    let router = make_router(sender);
+    tokio::spawn(async move { router.run(&mut rpc2).await });
+
+    let subscription = rpc1.subscribe::<u32>("counter").await.unwrap();
+    select! {
+        response = subscription.collect::<Vec<krossbar_rpc::Result<u32>>>().fuse() => {
+            let values: Vec<u32> = response.into_iter().map(Result::unwrap).collect();
+            assert_eq!(values, vec![0, 1, 2]);
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    // Subscription to a call endpoint
+    let mut subscription = rpc1.subscribe::<u32>("echo").await.unwrap();
+    select! {
+        response = subscription.next() => {
+            assert!(matches!(response, Some(Err(krossbar_rpc::Error::NoEndpoint))))
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
+
+#[tokio::test]
+async fn test_router_connections() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let router = Router::new().on_connection(CLIENT_NAME, move |client_name, _, _stream| {
+        let sender = sender.clone();
+        async move { sender.send(client_name).unwrap() }
+    });
+    tokio::spawn(async move { router.run(&mut rpc2).await });
+
+    let (_send_stream1, send_stream2) = UnixStream::pair().unwrap();
+    rpc1.connection_request("rpc1", CLIENT_NAME, vec![send_stream2.try_into().unwrap()])
+        .await
+        .unwrap();
+
+    assert_eq!(receiver.recv().await.unwrap(), "rpc1");
+}
+
+#[tokio::test]
+async fn test_router_serve() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+    let writer2 = rpc2.writer().clone();
+
+    let (sender, mut receiver) = mpsc::unbounded_channel::<()>();
+    let router = Router::new()
+        .on_call("slow", move |_: ()| {
+            let sender = sender.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                sender.send(()).unwrap();
+                Ok(())
+            }
+        })
+        .on_call("fast", |_: ()| async move { Ok(()) });
+    tokio::spawn(async move { router.serve(&mut rpc2, 2).await });
+
+    let slow_call = rpc1.call::<(), ()>("slow", &()).await.unwrap();
+    let fast_call = rpc1.call::<(), ()>("fast", &()).await.unwrap();
+    select! {
+        response = fast_call.fuse() => {
+            response.unwrap();
+            // Slow handler didn't block the fast one
+            assert!(receiver.try_recv().is_err());
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    // Service outgoing calls are resolved while the slow handler is running
+    let mut service_call = writer2.call::<u32, u32>("echo", &42).await.unwrap().fuse();
+    loop {
+        select! {
+            response = service_call => {
+                assert_eq!(response.unwrap(), 42);
+                break;
+            },
+            request = rpc1.poll().fuse() => {
+                let request = request.unwrap();
+                request.respond(Ok(42u32)).await;
+            }
+        }
+    }
+    assert!(receiver.try_recv().is_err());
+
+    select! {
+        response = slow_call.fuse() => response.unwrap(),
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
+
+#[tokio::test]
+async fn test_router_serve_concurrency_limit() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+
+    let router = Router::new().on_call("slow", {
+        let running = running.clone();
+        let max_running = max_running.clone();
+
+        move |_: ()| {
+            let running = running.clone();
+            let max_running = max_running.clone();
+
+            async move {
+                let current = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(current, Ordering::SeqCst);
+
+                tokio::time::sleep(Duration::from_millis(50)).await;
+
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+    });
+    tokio::spawn(async move { router.serve(&mut rpc2, 2).await });
+
+    let mut calls = Vec::new();
+    for _ in 0..6 {
+        calls.push(rpc1.call::<(), ()>("slow", &()).await.unwrap());
+    }
+
+    select! {
+        responses = futures::future::join_all(calls).fuse() => {
+            assert!(responses.into_iter().all(|r| r.is_ok()))
+        },
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    assert_eq!(max_running.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_router_long_subscription() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+
+    let router = Router::new()
+        .on_call("echo", |value: u32| async move { Ok(value) })
+        .on_subscription("endless", |sink| async move {
+            sink.send(42u32).await;
+            futures::future::pending::<()>().await
+        });
+    tokio::spawn(async move { router.run(&mut rpc2).await });
+
+    let mut subscription = rpc1.subscribe::<u32>("endless").await.unwrap();
+    let call = rpc1.call::<u32, u32>("echo", &11).await.unwrap();
+
+    // Calls are still handled while the subscription is alive
+    select! {
+        response = call.fuse() => assert_eq!(response.unwrap(), 11),
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    select! {
+        value = subscription.next() => assert_eq!(value.unwrap().unwrap(), 42),
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
+
+#[tokio::test]
+#[should_panic]
+async fn test_router_serve_zero_concurrency() {
+    let (_stream1, stream2) = UnixStream::pair().unwrap();
+    let mut rpc = Rpc::new(stream2, "service");
+
+    Router::new().serve(&mut rpc, 0).await;
+}
+
+#[tokio::test]
+async fn test_router_connection_fds() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let router = Router::new().on_connection_fds(CLIENT_NAME, move |_, _, fds| {
+        let sender = sender.clone();
+        async move { sender.send(fds).unwrap() }
+    });
+    tokio::spawn(async move { router.run(&mut rpc2).await });
+
+    // Connection request to an unknown target is dropped. FDs are closed
+    let (mut unknown_peer, unknown_stream) = UnixStream::pair().unwrap();
+    rpc1.connection_request("rpc1", "unknown", vec![unknown_stream.try_into().unwrap()])
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; 1];
+    assert_eq!(unknown_peer.read(&mut buf).await.unwrap(), 0);
+
+    // Connection handler receives all FDs
+    let (stream3, stream4) = UnixStream::pair().unwrap();
+    let fds = vec![stream3.try_into().unwrap(), stream4.try_into().unwrap()];
+    rpc1.connection_request("rpc1", CLIENT_NAME, fds)
+        .await
+        .unwrap();
+
+    let fds = receiver.recv().await.unwrap();
+    assert_eq!(fds.len(), 2);
+    assert!(fds.iter().all(|fd| fd.kind() == FdKind::UnixStream));
+}
+
+#[tokio::test]
+async fn test_router_serve_nested_call() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "client");
+    let mut rpc2 = Rpc::new(stream2, "service");
+    let writer2 = rpc2.writer().clone();
+
+    // Handler calls the client back over the same connection
+    let router = Router::new()
+        .on_call("nested", move |value: u32| {
+            let writer = writer2.clone();
+            async move { writer.call::<u32, u32>("echo", &value).await?.await }
+        })
+        .on_subscription("endless", |sink| async move {
+            sink.send(42u32).await;
+            futures::future::pending::<()>().await
+        });
+    tokio::spawn(async move { router.serve(&mut rpc2, 1).await });
+
+    // Subscription doesn't occupy the only slot
+    let mut subscription = rpc1.subscribe::<u32>("endless").await.unwrap();
+    let mut call = rpc1.call::<u32, u32>("nested", &11).await.unwrap().fuse();
+
+    let requests = async {
+        loop {
+            select! {
+                response = call => {
+                    assert_eq!(response.unwrap(), 11);
+                    break;
+                },
+                request = rpc1.poll().fuse() => {
+                    let mut request = request.unwrap();
+                    let Some(Body::Call(params)) = request.take_body() else {
+                        panic!("Invalid message type")
+                    };
+                    request.respond(Ok(params)).await;
+                }
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), requests)
+        .await
+        .unwrap();
+
+    select! {
+        value = subscription.next() => assert_eq!(value.unwrap().unwrap(), 42),
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+}
@@ -0,0 +1,158 @@
+use futures::{select, FutureExt};
+use tokio::{io::AsyncReadExt, net::UnixStream};
+
+use krossbar_rpc::{
+    handshake::HandshakeOptions, request::Body, rpc::Rpc, Disconnect, RpcData, RpcMessage,
+};
+
+const ENDPOINT_NAME: &str = "test_function";
+const THRESHOLD: usize = 1024;
+
+/// Read a raw BSON message frame
+async fn read_frame(stream: &mut UnixStream) -> RpcMessage {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf).await.unwrap();
+
+    let mut frame = vec![0u8; i32::from_le_bytes(len_buf) as usize];
+    frame[..4].copy_from_slice(&len_buf);
+    stream.read_exact(&mut frame[4..]).await.unwrap();
+
+    krossbar_rpc::bson::from_slice(&frame).unwrap()
+}
+
+async fn test_echo_call(mut rpc1: Rpc, mut rpc2: Rpc, payload: String) {
+    let call = rpc1
+        .call::<String, String>(ENDPOINT_NAME, &payload)
+        .await
+        .unwrap();
+
+    // Poll the stream to receive the request
+    let mut request = rpc2.poll().await.unwrap();
+    assert_eq!(request.endpoint(), ENDPOINT_NAME);
+
+    let Some(Body::Call(params)) = request.take_body() else {
+        panic!("Invalid message type")
+    };
+    assert_eq!(params.as_str().unwrap(), payload);
+
+    assert!(request.respond(Ok(params)).await);
+
+    select! {
+        response = call.fuse() => assert_eq!(response.unwrap(), payload),
+        _ = rpc1.poll().fuse() => panic!("Unexpected disconnect")
+    }
+}
+
+#[tokio::test]
+async fn test_shared_memory_call() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc1").with_shared_memory(THRESHOLD);
+    let rpc2 = Rpc::new(stream2, "rpc2").with_shared_memory(THRESHOLD);
+
+    test_echo_call(rpc1, rpc2, "x".repeat(1024 * 1024)).await
+}
+
+#[tokio::test]
+async fn test_shared_memory_reference() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, mut stream2) = UnixStream::pair().unwrap();
+
+    let rpc = Rpc::new(stream1, "rpc").with_shared_memory(THRESHOLD);
+
+    // Small messages are sent inline
+    rpc.send_message(ENDPOINT_NAME, &"small").await.unwrap();
+    rpc.send_message(ENDPOINT_NAME, &"x".repeat(THRESHOLD * 64))
+        .await
+        .unwrap();
+
+    let small = read_frame(&mut stream2).await;
+    assert!(matches!(small.data, RpcData::Message { .. }));
+
+    // Large message is replaced with a reference
+    let large = read_frame(&mut stream2).await;
+    assert!(matches!(
+        large.data,
+        RpcData::SharedMemory { size } if size as usize > THRESHOLD * 64
+    ));
+}
+
+#[tokio::test]
+async fn test_shared_memory_unsupported() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = tokio::io::duplex(1024 * 1024);
+
+    // The transport doesn't support FD passing. Large messages are sent inline
+    let rpc1 = Rpc::new(stream1, "rpc1").with_shared_memory(THRESHOLD);
+    let rpc2 = Rpc::new(stream2, "rpc2").with_shared_memory(THRESHOLD);
+
+    test_echo_call(rpc1, rpc2, "x".repeat(THRESHOLD * 64)).await
+}
+
+#[tokio::test]
+async fn test_shared_memory_reconnect_unsupported() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let peer = tokio::spawn(Rpc::handshake(stream2, HandshakeOptions::new("service")));
+    let mut rpc = Rpc::handshake(stream1, HandshakeOptions::new("client"))
+        .await
+        .unwrap()
+        .with_shared_memory(THRESHOLD);
+    let _peer = peer.await.unwrap().unwrap();
+    assert!(rpc.peer_info().unwrap().features.shared_memory);
+
+    // Reconnect to a peer, which can't receive shared memory messages
+    let (stream1, stream2) = tokio::io::duplex(1024 * 1024);
+
+    let peer = tokio::spawn(Rpc::handshake(
+        stream2,
+        HandshakeOptions::new("new_service"),
+    ));
+    let new_rpc = Rpc::handshake(stream1, HandshakeOptions::new("client"))
+        .await
+        .unwrap();
+    let peer = peer.await.unwrap().unwrap();
+
+    rpc.on_reconnected(new_rpc).await;
+    assert_eq!(rpc.peer_name(), "new_service");
+    assert!(!rpc.peer_info().unwrap().features.shared_memory);
+
+    // Large messages are sent inline
+    test_echo_call(rpc, peer, "x".repeat(THRESHOLD * 64)).await
+}
+
+#[tokio::test]
+async fn test_shared_memory_not_enabled() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    // Receiver didn't enable or negotiate shared memory
+    let rpc1 = Rpc::new(stream1, "rpc1").with_shared_memory(THRESHOLD);
+    let mut rpc2 = Rpc::new(stream2, "rpc2");
+
+    rpc1.send_message(ENDPOINT_NAME, &"x".repeat(THRESHOLD * 64))
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        rpc2.poll().await,
+        Err(Disconnect::UnknownMessage(_))
+    ));
+}
@@ -1,5 +1,12 @@
-use futures::{select, FutureExt, StreamExt};
-use krossbar_rpc::{request::Body, rpc::Rpc};
+use std::{collections::HashSet, time::Duration};
+
+use futures::{select, stream::FusedStream, FutureExt, StreamExt};
+use krossbar_rpc::{
+    request::Body,
+    rpc::Rpc,
+    subscription::{OverflowPolicy, Subscription, SubscriptionOptions},
+    Disconnect,
+};
 use tokio::net::UnixStream;
 
 const ENDPOINT_NAME: &str = "test_function";
@@ -106,6 +113,45 @@ async fn test_subscription_reconnect() {
     }
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_subscription_reconnect_no_duplicates() {
+    const SUBSCRIPTIONS: usize = 64;
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let _rpc2 = Rpc::new(stream2, "rpc");
+
+    // Subscribe while reconnecting
+    let writer = rpc1.writer().clone();
+    let subscribe = tokio::spawn(async move {
+        let mut subscriptions = Vec::new();
+        for _ in 0..SUBSCRIPTIONS {
+            subscriptions.push(writer.subscribe::<u32>(ENDPOINT_NAME).await.unwrap());
+            tokio::task::yield_now().await;
+        }
+
+        subscriptions
+    });
+
+    let (stream1, stream3) = UnixStream::pair().unwrap();
+    rpc1.on_reconnected(Rpc::new(stream1, "rpc")).await;
+
+    let _subscriptions = subscribe.await.unwrap();
+    let mut rpc3 = Rpc::new(stream3, "rpc");
+
+    // Every subscription is sent into the new connection exactly once
+    let mut ids = HashSet::new();
+    for _ in 0..SUBSCRIPTIONS {
+        let request = rpc3.poll().await.unwrap();
+        assert!(ids.insert(request.message_id()));
+    }
+
+    assert!(tokio::time::timeout(Duration::from_millis(50), rpc3.poll())
+        .await
+        .is_err());
+}
+
 #[tokio::test]
 async fn test_subscription_reconnect_new_request() {
     let _ = pretty_env_logger::formatted_builder()
@@ -152,10 +198,7 @@ async fn test_subscription_reconnect_new_request() {
 
         let subscription2 = rpc1.subscribe::<u32>(ENDPOINT_NAME).await.unwrap();
 
-        // This is a resubscription request
-        let _ = rpc3.poll().await.unwrap();
-
-        // This is a new subscription request
+        // Dropped subscription is not resent. This is a new subscription request
         let mut sub2_request = rpc3.poll().await.unwrap();
         assert_eq!(sub2_request.endpoint(), ENDPOINT_NAME);
 
@@ -173,3 +216,280 @@ async fn test_subscription_reconnect_new_request() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_unsubscribe_on_drop() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let subscription = rpc1.subscribe::<u32>(ENDPOINT_NAME).await.unwrap();
+
+    let request = rpc2.poll().await.unwrap();
+    assert!(!request.is_cancelled());
+
+    drop(subscription);
+
+    select! {
+        _ = request.cancelled().fuse() => {
+            assert!(request.is_cancelled());
+        },
+        _ = rpc2.poll().fuse() => {
+            panic!("Should not return here")
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_subscription_close() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let mut subscription = rpc1.subscribe::<u32>(ENDPOINT_NAME).await.unwrap();
+
+    let request = rpc2.poll().await.unwrap();
+    assert!(request.respond(Ok(420)).await);
+
+    select! {
+        response = subscription.next() => {
+            assert!(matches!(response.unwrap(), Ok(420)));
+        },
+        _ = rpc1.poll().fuse() => {}
+    };
+
+    subscription.close().await;
+
+    select! {
+        _ = request.cancelled().fuse() => {},
+        _ = rpc2.poll().fuse() => {
+            panic!("Should not return here")
+        }
+    }
+
+    // Late subscription responses are ignored
+    assert!(request.respond(Ok(421)).await);
+
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+    let call_request = rpc2.poll().await.unwrap();
+    assert!(call_request.respond(Ok(422)).await);
+
+    select! {
+        response = call.fuse() => {
+            assert_eq!(response.unwrap(), 422);
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+}
+
+#[tokio::test]
+async fn test_subscription_sink_close() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let subscription = rpc1.subscribe::<u32>(ENDPOINT_NAME).await.unwrap();
+
+    let sink = rpc2.poll().await.unwrap().into_subscription::<u32>();
+    assert_eq!(sink.endpoint(), ENDPOINT_NAME);
+
+    assert!(sink.send(420).await);
+    assert!(sink.send(421).await);
+    assert!(sink.close().await);
+
+    let response = select! {
+        response = subscription.collect::<Vec<krossbar_rpc::Result<u32>>>().fuse() => response,
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    };
+
+    assert_eq!(response.len(), 2);
+    assert!(matches!(response[0], Ok(420)));
+    assert!(matches!(response[1], Ok(421)));
+}
+
+#[tokio::test]
+async fn test_subscription_sink_error() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let mut subscription = rpc1.subscribe::<u32>(ENDPOINT_NAME).await.unwrap();
+
+    let sink = rpc2.poll().await.unwrap().into_subscription::<u32>();
+
+    assert!(sink.send(420).await);
+    assert!(
+        sink.error(krossbar_rpc::Error::ClientError("Test error".to_owned()))
+            .await
+    );
+
+    let response = select! {
+        response = (&mut subscription).collect::<Vec<krossbar_rpc::Result<u32>>>().fuse() => response,
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    };
+
+    assert_eq!(response.len(), 2);
+    assert!(matches!(response[0], Ok(420)));
+    assert!(matches!(
+        response[1],
+        Err(krossbar_rpc::Error::ClientError(_))
+    ));
+    assert!(subscription.is_terminated());
+}
+
+#[tokio::test]
+async fn test_subscription_drop_oldest() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let options = SubscriptionOptions::new()
+        .buffer_size(2)
+        .overflow_policy(OverflowPolicy::DropOldest);
+    let subscription = rpc1
+        .subscribe_with_options::<u32>(ENDPOINT_NAME, &options)
+        .await
+        .unwrap();
+
+    let sink = rpc2.poll().await.unwrap().into_subscription::<u32>();
+    for i in 0..5 {
+        assert!(sink.send(i).await);
+    }
+    assert!(sink.close().await);
+    drop(rpc2);
+
+    // Read all the responses without consuming the subscription
+    assert!(matches!(rpc1.poll().await, Err(Disconnect::Closed)));
+
+    let response: Vec<u32> = subscription.map(Result::unwrap).collect().await;
+    assert_eq!(response, vec![3, 4]);
+}
+
+/// Send a few subscription items, which the subscriber doesn't read, and make a call after them.
+/// Returns the unread subscription
+async fn test_slow_subscriber(policy: OverflowPolicy) -> Subscription<u32> {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let options = SubscriptionOptions::new()
+        .buffer_size(1)
+        .overflow_policy(policy);
+    let subscription = rpc1
+        .subscribe_with_options::<u32>(ENDPOINT_NAME, &options)
+        .await
+        .unwrap();
+    let sink = rpc2.poll().await.unwrap().into_subscription::<u32>();
+
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+    let call_request = rpc2.poll().await.unwrap();
+
+    for i in 0..5 {
+        assert!(sink.send(i).await);
+    }
+    assert!(call_request.respond(Ok(420)).await);
+
+    select! {
+        response = call.fuse() => assert_eq!(response.unwrap(), 420),
+        _ = rpc1.poll().fuse() => panic!("Should not return here")
+    }
+
+    subscription
+}
+
+#[tokio::test]
+async fn test_slow_subscriber_doesnt_block_calls() {
+    let mut subscription = test_slow_subscriber(OverflowPolicy::CoalesceLatest).await;
+    assert_eq!(subscription.next().await.unwrap().unwrap(), 4);
+}
+
+#[tokio::test]
+async fn test_unbounded_slow_subscriber_doesnt_block_calls() {
+    let subscription = test_slow_subscriber(OverflowPolicy::Unbounded).await;
+
+    let values: Vec<u32> = subscription.take(5).map(Result::unwrap).collect().await;
+    assert_eq!(values, vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+#[should_panic]
+fn test_subscription_zero_buffer_size() {
+    SubscriptionOptions::new().buffer_size(0);
+}
+
+#[tokio::test]
+async fn test_subscription_overflow_disconnect() {
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let options = SubscriptionOptions::new()
+        .buffer_size(1)
+        .overflow_policy(OverflowPolicy::Disconnect);
+    let subscription = rpc1
+        .subscribe_with_options::<u32>(ENDPOINT_NAME, &options)
+        .await
+        .unwrap();
+    tokio::spawn(async move { while rpc1.poll().await.is_ok() {} });
+
+    let sink = rpc2.poll().await.unwrap().into_subscription::<u32>();
+    for i in 0..3 {
+        assert!(sink.send(i).await);
+    }
+
+    // Overflowed subscription unsubscribes from the peer
+    select! {
+        _ = sink.cancelled().fuse() => {},
+        _ = rpc2.poll().fuse() => panic!("Should not return here")
+    }
+
+    let response = subscription
+        .collect::<Vec<krossbar_rpc::Result<u32>>>()
+        .await;
+
+    assert_eq!(response.len(), 2);
+    assert!(matches!(response[0], Ok(0)));
+    assert!(matches!(
+        response[1],
+        Err(krossbar_rpc::Error::SubscriptionOverflow)
+    ));
+}
+
+#[tokio::test]
+async fn test_subscription_rpc_dropped() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc");
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    let mut subscription = rpc1.subscribe::<u32>(ENDPOINT_NAME).await.unwrap();
+
+    let request = rpc2.poll().await.unwrap();
+    assert!(request.respond(Ok(42)).await);
+
+    select! {
+        response = subscription.next() => assert!(matches!(response, Some(Ok(42)))),
+        _ = rpc1.poll().fuse() => panic!("Unexpected disconnect")
+    }
+
+    // Peer disconnects without closing the subscription
+    drop(request);
+    drop(rpc2);
+    assert!(rpc1.poll().await.is_err());
+
+    // Subscription is kept for reconnection until the handle is dropped
+    drop(rpc1);
+    assert!(subscription.next().await.is_none());
+    assert!(subscription.is_terminated());
+}
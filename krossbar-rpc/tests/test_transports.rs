@@ -0,0 +1,86 @@
+use futures::{select, FutureExt};
+use krossbar_rpc::{request::Body, rpc::Rpc};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+
+const ENDPOINT_NAME: &str = "test_function";
+
+async fn test_pair_call(mut rpc1: Rpc, mut rpc2: Rpc) {
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+
+    // Poll the stream to receive the request
+    let mut request = rpc2.poll().await.unwrap();
+    assert_eq!(request.endpoint(), ENDPOINT_NAME);
+
+    if let Some(Body::Call(bson)) = request.take_body() {
+        let request_body: u32 = bson::from_bson(bson).unwrap();
+        assert_eq!(request_body, 42);
+    } else {
+        panic!("Invalid message type")
+    }
+
+    assert!(request.respond(Ok(420)).await);
+
+    select! {
+        response = call.fuse() => {
+            assert_eq!(response.unwrap(), 420);
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+}
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let (client, server) = futures::join!(TcpStream::connect(address), listener.accept());
+    (client.unwrap(), server.unwrap().0)
+}
+
+#[tokio::test]
+async fn test_tcp_call() {
+    let (stream1, stream2) = tcp_pair().await;
+
+    test_pair_call(Rpc::new(stream1, "rpc1"), Rpc::new(stream2, "rpc2")).await
+}
+
+#[tokio::test]
+async fn test_duplex_call() {
+    let (stream1, stream2) = tokio::io::duplex(1024);
+
+    test_pair_call(Rpc::new(stream1, "rpc1"), Rpc::new(stream2, "rpc2")).await
+}
+
+#[tokio::test]
+async fn test_split_pair_call() {
+    let (stream1, stream2) = tokio::io::duplex(1024);
+
+    test_pair_call(
+        Rpc::new(tokio::io::split(stream1), "rpc1"),
+        Rpc::new(tokio::io::split(stream2), "rpc2"),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_fd_passing_unsupported() {
+    let (stream1, stream2) = tokio::io::duplex(1024);
+
+    let rpc1 = Rpc::new(stream1, "rpc1");
+    let rpc2 = Rpc::new(stream2, "rpc2");
+    assert!(!rpc1.supports_fd_passing().await);
+
+    let (_, send_stream) = UnixStream::pair().unwrap();
+    assert!(matches!(
+        rpc1.connection_request("rpc1", "rpc2", vec![send_stream.try_into().unwrap()])
+            .await,
+        Err(krossbar_rpc::Error::NotSupported)
+    ));
+
+    assert!(matches!(
+        rpc1.call_fd::<u32, u32>(ENDPOINT_NAME, &42).await,
+        Err(krossbar_rpc::Error::NotSupported)
+    ));
+
+    // Connection is still usable
+    test_pair_call(rpc1, rpc2).await;
+}
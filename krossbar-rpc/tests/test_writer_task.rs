@@ -0,0 +1,238 @@
+use std::time::Duration;
+
+use futures::{select, FutureExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+use krossbar_rpc::{request::Body, rpc::Rpc, writer::RpcWriter};
+
+const ENDPOINT_NAME: &str = "test_function";
+const QUEUE_CAPACITY: usize = 4;
+
+/// Wait until the writer task is blocked, and the write queue is full
+async fn wait_queue_full(writer: &RpcWriter) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while writer.write_queue_metrics().unwrap().depth < QUEUE_CAPACITY {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_writer_task_call() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc").with_writer_task(QUEUE_CAPACITY);
+    let mut rpc2 = Rpc::new(stream2, "rpc").with_writer_task(QUEUE_CAPACITY);
+
+    assert_eq!(
+        rpc1.writer().write_queue_metrics().unwrap().capacity,
+        QUEUE_CAPACITY
+    );
+
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+
+    let mut request = rpc2.poll().await.unwrap();
+    assert_eq!(request.endpoint(), ENDPOINT_NAME);
+
+    if let Some(Body::Call(bson)) = request.take_body() {
+        let request_body: u32 = bson::from_bson(bson).unwrap();
+        assert_eq!(request_body, 42);
+    } else {
+        panic!("Invalid message type")
+    }
+
+    assert!(request.respond(Ok(420)).await);
+
+    select! {
+        response = call.fuse() => {
+            assert_eq!(response.unwrap(), 420);
+        },
+        _ = rpc1.poll().fuse() => {}
+    }
+
+    // Streams are sent by the writer task right after their messages
+    let (mut send_stream1, send_stream2) = UnixStream::pair().unwrap();
+    rpc1.connection_request("rpc1", "rpc2", vec![send_stream2.try_into().unwrap()])
+        .await
+        .unwrap();
+
+    let mut request = rpc2.poll().await.unwrap();
+    let Some(Body::Fd { mut fds, .. }) = request.take_body() else {
+        panic!("Invalid message type")
+    };
+    let mut stream = fds.pop().unwrap().into_unix_stream().unwrap();
+
+    send_stream1.write_all(b"ping").await.unwrap();
+    let mut buffer = [0u8; 4];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+}
+
+#[tokio::test]
+async fn test_writer_task_queue_depth() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    const NUM_MESSAGES: usize = 20;
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc").with_writer_task(QUEUE_CAPACITY);
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    // Send more data than the socket buffer can hold while the peer isn't reading
+    let writer = rpc1.writer().clone();
+    tokio::spawn(async move {
+        let payload = "x".repeat(64 * 1024);
+
+        for i in 0..NUM_MESSAGES {
+            writer
+                .send_message(ENDPOINT_NAME, &(i, &payload))
+                .await
+                .unwrap();
+        }
+    });
+
+    // Writer task is blocked, and the queue gets full
+    wait_queue_full(rpc1.writer()).await;
+
+    let metrics = rpc1.writer().write_queue_metrics().unwrap();
+    assert_eq!(metrics.high_priority_depth, 0);
+
+    for i in 0..NUM_MESSAGES {
+        let mut request = rpc2.poll().await.unwrap();
+
+        if let Some(Body::Message(bson)) = request.take_body() {
+            let (index, _): (usize, String) = bson::from_bson(bson).unwrap();
+            assert_eq!(index, i);
+        } else {
+            panic!("Invalid message type")
+        }
+    }
+
+    let metrics = rpc1.writer().write_queue_metrics().unwrap();
+    assert_eq!(metrics.depth, 0);
+    assert_eq!(metrics.peak_depth, QUEUE_CAPACITY);
+}
+
+#[tokio::test]
+async fn test_writer_task_flush_on_close() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc").with_writer_task(QUEUE_CAPACITY);
+    let mut rpc2 = Rpc::new(stream2, "rpc");
+
+    rpc1.send_message(ENDPOINT_NAME, &42u32).await.unwrap();
+    rpc1.writer().flush().await;
+
+    // Queued messages are written even if the writer is dropped
+    rpc1.send_message(ENDPOINT_NAME, &43u32).await.unwrap();
+    drop(rpc1);
+
+    for expected in [42u32, 43] {
+        let mut request = rpc2.poll().await.unwrap();
+
+        if let Some(Body::Message(bson)) = request.take_body() {
+            let body: u32 = bson::from_bson(bson).unwrap();
+            assert_eq!(body, expected);
+        } else {
+            panic!("Invalid message type")
+        }
+    }
+
+    assert!(rpc2.poll().await.is_err());
+}
+
+#[tokio::test]
+async fn test_writer_task_reconnect_after_violation() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, mut stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc").with_writer_task(QUEUE_CAPACITY);
+
+    // Invalid message length closes the connection
+    stream2.write_all(&i32::MAX.to_le_bytes()).await.unwrap();
+    assert!(rpc1.poll().await.unwrap_err().is_protocol_violation());
+
+    let (stream1, stream3) = UnixStream::pair().unwrap();
+    rpc1.on_reconnected(Rpc::new(stream1, "rpc")).await;
+    let mut rpc3 = Rpc::new(stream3, "rpc");
+
+    // Writer task is restarted
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+
+    let request = rpc3.poll().await.unwrap();
+    assert!(request.respond(Ok(420)).await);
+
+    select! {
+        response = call.fuse() => assert_eq!(response.unwrap(), 420),
+        _ = rpc1.poll().fuse() => panic!("Unexpected disconnect")
+    }
+}
+
+#[tokio::test]
+async fn test_writer_task_close_peer_not_reading() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, mut stream2) = UnixStream::pair().unwrap();
+
+    let mut rpc1 = Rpc::new(stream1, "rpc").with_writer_task(QUEUE_CAPACITY);
+
+    // Fill the socket buffer and the queue while the peer isn't reading
+    let writer = rpc1.writer().clone();
+    tokio::spawn(async move {
+        let payload = "x".repeat(64 * 1024);
+        while writer.send_message(ENDPOINT_NAME, &payload).await.is_ok() {}
+    });
+
+    wait_queue_full(rpc1.writer()).await;
+
+    // Closing the connection doesn't wait for the pending messages forever
+    stream2.write_all(&i32::MAX.to_le_bytes()).await.unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(5), rpc1.poll())
+        .await
+        .unwrap();
+    assert!(result.unwrap_err().is_protocol_violation());
+}
+
+#[tokio::test]
+async fn test_writer_task_failed_call() {
+    let _ = pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Debug)
+        .try_init();
+
+    let (stream1, stream2) = UnixStream::pair().unwrap();
+
+    let rpc1 = Rpc::new(stream1, "rpc").with_writer_task(QUEUE_CAPACITY);
+    drop(stream2);
+
+    // Call is queued, but fails to be written
+    let call = rpc1.call::<u32, u32>(ENDPOINT_NAME, &42).await.unwrap();
+
+    assert!(matches!(
+        tokio::time::timeout(Duration::from_secs(1), call)
+            .await
+            .unwrap(),
+        Err(krossbar_rpc::Error::PeerDisconnected)
+    ));
+}
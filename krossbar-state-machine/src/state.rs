@@ -50,7 +50,7 @@ where
     where
         NRet: 'static,
     {
-        self.map(move |value| func(value))
+        self.map(func)
     }
 }
 